@@ -0,0 +1,32 @@
+use std::collections::VecDeque;
+
+/// Allocation policy over block offsets, used by [`BlockStorage`](crate::block_storage::BlockStorage)
+/// to reuse freed blocks.
+///
+/// The allocator only manages blocks handed back through `deallocate`; when
+/// `allocate` returns `None` the storage extends the file instead. Its state is
+/// persisted in the meta block through `serialize`/`deserialize`.
+pub trait Allocator {
+  /// Takes `count` contiguous free blocks and returns the offset of the first one.
+  fn allocate(&mut self, count: u64) -> Option<u64>;
+
+  /// Returns the offset `allocate(count)` would return, without taking the blocks.
+  fn peek(&self, count: u64) -> Option<u64>;
+
+  /// Marks `count` blocks starting at `offset` as free.
+  fn deallocate(&mut self, offset: u64, count: u64);
+
+  /// Whether any block of `[offset, offset + count)` is currently free.
+  fn overlaps_free(&self, offset: u64, count: u64) -> bool;
+
+  fn serialize(&self) -> VecDeque<u8>;
+
+  fn deserialize(&mut self, data: &mut VecDeque<u8>);
+
+  /// Whether the state changed since it was last persisted.
+  fn is_dirty(&self) -> bool {
+    true
+  }
+
+  fn mark_clean(&mut self) {}
+}
@@ -0,0 +1,133 @@
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  process,
+};
+
+/// Replaces the file at `to` with a copy of `from`, so that a crash leaves
+/// either the old or the new file intact at `to`, never a partial one.
+///
+/// See [`atomic_write`] for how the file is swapped in.
+pub fn atomic_replace(from: &Path, to: &Path) -> io::Result<()> {
+  let mut source = fs::File::open(from)?;
+  atomic_write(to, |temp| io::copy(&mut source, temp).map(|_| ()))
+}
+
+/// Replaces the file at `to` with what `write` writes, so that a crash leaves
+/// either the old or the new file intact at `to`, never a partial one.
+///
+/// `write` writes to a temporary file next to `to`, which is synced before it is
+/// renamed over `to`; the directory is synced afterwards to persist the rename.
+/// The temporary name is unique, so concurrent replaces of the same target don't
+/// write to the same temporary file; the last rename wins.
+pub fn atomic_write(
+  to: &Path,
+  write: impl FnOnce(&mut fs::File) -> io::Result<()>,
+) -> io::Result<()> {
+  let temp = temp_path(to);
+  let result = fs::File::options()
+    .write(true)
+    .create_new(true)
+    .open(&temp)
+    .and_then(|mut file| {
+      write(&mut file)?;
+      file.flush()?;
+      file.sync_all()
+    })
+    .and_then(|_| fs::rename(&temp, to));
+  if result.is_err() {
+    let _ = fs::remove_file(&temp);
+  }
+  result?;
+  sync_dir(to)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+  let mut name = path.file_name().unwrap_or_default().to_os_string();
+  name.push(format!(
+    ".{}.{:016x}.tmp",
+    process::id(),
+    rand::random::<u64>()
+  ));
+  path.with_file_name(name)
+}
+
+/// Syncs the directory containing `path`, making its creation or rename durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+  let dir = match path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new("."),
+  };
+  fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files here; the rename is left to the OS.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::atomic_replace;
+  use rand::{self, Rng};
+  use std::env::temp_dir;
+  use std::{fs, path::Path, thread};
+
+  fn temp_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+      .unwrap()
+      .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+      .count()
+  }
+
+  #[test]
+  fn test_atomic_replace() {
+    let mut rng = rand::thread_rng();
+    let dir = temp_dir().join(format!("leafless_replace_{}", rng.gen::<u64>()));
+    fs::create_dir(&dir).unwrap();
+    let from = dir.join("new");
+    let to = dir.join("target");
+    fs::write(&from, b"new contents").unwrap();
+    fs::write(&to, b"old").unwrap();
+
+    atomic_replace(&from, &to).unwrap();
+    assert_eq!(fs::read(&to).unwrap(), b"new contents");
+    assert_eq!(temp_files(&dir), 0);
+    assert_eq!(fs::read(&from).unwrap(), b"new contents");
+
+    assert!(atomic_replace(&dir.join("missing"), &to).is_err());
+    assert_eq!(fs::read(&to).unwrap(), b"new contents");
+    assert_eq!(temp_files(&dir), 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_concurrent_replace() {
+    let mut rng = rand::thread_rng();
+    let dir = temp_dir().join(format!("leafless_replace_{}", rng.gen::<u64>()));
+    fs::create_dir(&dir).unwrap();
+    let to = dir.join("target");
+    let sources = (0..8u8)
+      .map(|i| {
+        let from = dir.join(format!("source{}", i));
+        fs::write(&from, vec![i; 64 * 1024]).unwrap();
+        from
+      })
+      .collect::<Vec<_>>();
+    thread::scope(|scope| {
+      for from in &sources {
+        let to = &to;
+        scope.spawn(move || atomic_replace(from, to).unwrap());
+      }
+    });
+    // Whichever rename came last, the target is one source in full.
+    let contents = fs::read(&to).unwrap();
+    assert_eq!(contents.len(), 64 * 1024);
+    assert!(contents.iter().all(|&byte| byte == contents[0]));
+    assert_eq!(temp_files(&dir), 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
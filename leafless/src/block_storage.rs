@@ -1,186 +1,4539 @@
 use std::{
   collections::VecDeque,
-  fs,
+  fmt, fs,
   io::{self, Read, Seek, Write},
+  marker::PhantomData,
+  path::Path,
+  rc::Rc,
+  sync::mpsc,
+  thread,
+  time::{Duration, Instant},
 };
 
-use crate::encoding::{Decoder, Encoder};
+use crate::{
+  allocator::Allocator,
+  atomic,
+  checksum::{self, Crc32, Fnv1a64},
+  encoding::{Decode, DecodeError, Decoder, Encode, Encoder, LeaflessVarint, VarintCodec},
+  error::LeaflessError,
+  free_list::FreeList,
+  metrics::Metrics,
+  transform::Transform,
+};
 
+/// Block size of storages created without [`StorageOptions::block_size`].
 const BLOCK_SIZE: u64 = 4096;
+/// Smallest block size a storage can be created with.
+const MIN_BLOCK_SIZE: u64 = 512;
+/// How often a write or flush is retried after a transient failure.
+const WRITE_RETRIES: usize = 3;
+/// Upper bound of the encoded fixed header fields at the start of the meta block.
+const HEADER_SIZE: u64 = 65;
+/// Version of the file layout, recorded in the header and checked on open.
+const FORMAT_VERSION: u64 = 1;
+/// Most blocks copied per record by [`BlockStorage::stream_to`].
+const STREAM_CHUNK_BLOCKS: u64 = 64;
+/// Bytes read at a time by [`BlockStorage::read_until`] while looking for the delimiter.
+const SCAN_CHUNK_SIZE: u64 = 256;
+/// Marks the start of a block written by [`BlockStorage::writeTaggedBlock`].
+const TAGGED_MAGIC: [u8; 4] = *b"LFTB";
 
 pub struct DataBlock {
   offset: u64,
   size: u64,
+  payload_offset: u64,
+  /// Block size of the storage, so the handle can be split by block count.
+  block_size: u64,
 }
 
 impl DataBlock {
+  /// Total size of the claimed blocks, including the reserved payload header.
   pub fn size(&self) -> u64 {
-    return self.size;
+    self.size
+  }
+
+  /// Offset of the payload from the start of the block.
+  ///
+  /// Block reads and writes are relative to this offset. It is non-zero only for
+  /// storages created with [`StorageOptions::payload_alignment`].
+  pub fn payload_offset(&self) -> u64 {
+    self.payload_offset
+  }
+
+  /// Number of payload bytes the block can hold.
+  pub fn payload_size(&self) -> u64 {
+    self.size - self.payload_offset
+  }
+
+  /// Splits the handle into one to its first `block_count` blocks and one to the
+  /// rest, e.g. for sub-allocators. Both stay claimed; nothing is read or written.
+  ///
+  /// # Panics
+  ///
+  /// Panics unless both parts hold at least one block, see
+  /// [`BlockStorage::split_block`] for a fallible version.
+  pub fn split_at(self, block_count: u64) -> (DataBlock, DataBlock) {
+    let count = self.size / self.block_size;
+    assert!(
+      block_count > 0 && block_count < count,
+      "split must leave at least one block on each side"
+    );
+    let split = block_count * self.block_size;
+    let rest = DataBlock {
+      offset: self.offset + block_count,
+      size: self.size - split,
+      ..self
+    };
+    (
+      DataBlock {
+        size: split,
+        ..self
+      },
+      rest,
+    )
+  }
+}
+
+/// A plain, persistable reference to a claimed block, e.g. for indexes.
+///
+/// Turned back into a [`DataBlock`] by [`BlockStorage::block_from_ref`], which
+/// validates it against the storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRef {
+  /// Index of the first block.
+  pub offset: u64,
+  /// Total size in bytes, as returned by [`DataBlock::size`].
+  pub size: u64,
+}
+
+impl From<&DataBlock> for BlockRef {
+  fn from(block: &DataBlock) -> BlockRef {
+    BlockRef {
+      offset: block.offset,
+      size: block.size,
+    }
+  }
+}
+
+impl Encode for BlockRef {
+  fn encode(&self, buf: &mut VecDeque<u8>) {
+    self.offset.encode(buf);
+    self.size.encode(buf);
+  }
+
+  fn encoded_size(&self) -> usize {
+    self.offset.encoded_size() + self.size.encoded_size()
+  }
+}
+
+impl Decode for BlockRef {
+  fn decode(data: &mut VecDeque<u8>) -> Result<BlockRef, DecodeError> {
+    Ok(BlockRef {
+      offset: u64::decode(data)?,
+      size: u64::decode(data)?,
+    })
+  }
+}
+
+/// The header fields of a storage file, as returned by [`BlockStorage::read_header`].
+pub struct Header {
+  block_size: u64,
+  block_count: u64,
+  meta_blocks: u64,
+  payload_alignment: u64,
+  transform: u64,
+  codec: u64,
+}
+
+impl Header {
+  pub fn block_size(&self) -> u64 {
+    self.block_size
+  }
+
+  /// Number of blocks claimed so far, including the meta block.
+  pub fn block_count(&self) -> u64 {
+    self.block_count
+  }
+
+  /// Number of blocks reserved for the meta region at the start of the file.
+  pub fn meta_blocks(&self) -> u64 {
+    self.meta_blocks
+  }
+
+  pub fn payload_alignment(&self) -> u64 {
+    self.payload_alignment
+  }
+
+  /// The [`Transform::id`] the storage was created with, 0 if none.
+  pub fn transform(&self) -> u64 {
+    self.transform
+  }
+
+  /// The [`VarintCodec::ID`] of the varints the storage writes.
+  pub fn codec(&self) -> u64 {
+    self.codec
+  }
+}
+
+/// A block that differs between two storages, see [`BlockStorage::diff`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockDiff {
+  /// The block is claimed in both storages with different contents.
+  Changed(u64),
+  /// The block is claimed only in the storage `diff` was called on.
+  OnlyInSelf(u64),
+  /// The block is claimed only in the other storage.
+  OnlyInOther(u64),
+}
+
+/// A point up to which everything written is durable, see [`BlockStorage::checkpoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Checkpoint {
+  /// Number of checkpoints taken by the handle before this one.
+  pub sequence: u64,
+  /// End of the claimed blocks when the checkpoint was taken.
+  pub offset: u64,
+}
+
+/// Where [`BlockStorage::compact_into`] moved each claimed region.
+#[derive(Debug, Default)]
+pub struct RemapTable {
+  /// Old offset, new offset and count of each region, ascending by old offset.
+  runs: Vec<(u64, u64, u64)>,
+}
+
+impl RemapTable {
+  /// The new offset of the block at `offset`, `None` if it wasn't claimed.
+  pub fn get(&self, offset: u64) -> Option<u64> {
+    let index = self.runs.partition_point(|&(start, _, _)| start <= offset);
+    let (start, target, count) = self.runs[index.checked_sub(1)?];
+    (offset < start + count).then(|| target + offset - start)
+  }
+
+  /// Rewrites `block_ref` to its new location, `None` if it wasn't claimed.
+  pub fn remap(&self, block_ref: BlockRef) -> Option<BlockRef> {
+    Some(BlockRef {
+      offset: self.get(block_ref.offset)?,
+      size: block_ref.size,
+    })
+  }
+}
+
+/// How the file is extended when a claim needs more blocks than it holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrowthPolicy {
+  /// Extend the file to exactly the claimed end.
+  Exact,
+  /// At least double the file length, so most claims don't resize the file.
+  /// The unused tail is handed out by later claims.
+  Geometric,
+}
+
+/// When written data is forced to disk with `sync_data`.
+///
+/// Writes always reach the file immediately; this only controls the fsync. Batched
+/// syncs are checked when writing, so an idle storage does not sync on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+  /// Never sync, leaving durability to the OS.
+  Never,
+  /// Sync after every write.
+  Always,
+  /// Sync on the first write once the duration passed since the last sync.
+  Interval(Duration),
+  /// Sync after this many writes.
+  Writes(u64),
+  /// Sync on whichever of [`SyncPolicy::Interval`] and [`SyncPolicy::Writes`] comes first.
+  IntervalOrWrites(Duration, u64),
+}
+
+/// What made a block fail verification, see [`CorruptionEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorruptionKind {
+  /// The block doesn't start with the tagged block magic.
+  Magic,
+  /// The header is truncated or its length exceeds the block.
+  Length,
+  /// The payload doesn't match the stored checksum.
+  Checksum,
+}
+
+impl fmt::Display for CorruptionKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CorruptionKind::Magic => write!(f, "Block is not tagged"),
+      CorruptionKind::Length => write!(f, "Tagged length exceeds the block"),
+      CorruptionKind::Checksum => write!(f, "Tagged block checksum mismatch"),
+    }
+  }
+}
+
+/// A block that failed verification, see [`BlockStorage::set_corruption_handler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorruptionEvent {
+  pub offset: u64,
+  pub kind: CorruptionKind,
+}
+
+/// See [`BlockStorage::set_corruption_handler`].
+pub type CorruptionHandler = Box<dyn Fn(&CorruptionEvent)>;
+
+/// When the callback of [`BlockStorage::set_compaction_policy`] fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionPolicy {
+  /// Fragmentation, as returned by [`BlockStorage::fragmentation`], to exceed.
+  pub fragmentation_threshold: f64,
+  /// Free blocks needed at least, so a few scattered frees don't trigger.
+  pub min_free_blocks: u64,
+}
+
+/// Called with the fragmentation and free block count, see
+/// [`BlockStorage::set_compaction_policy`].
+pub type CompactionCallback = Box<dyn FnMut(f64, u64)>;
+
+type CompactionCheck<A> = Box<dyn FnMut(&A)>;
+
+/// See [`BlockStorage::set_checksum_failure_handler`].
+pub type ChecksumFailureHandler = Box<dyn Fn(&DataBlock) -> Option<Vec<u8>>>;
+
+/// Options applied when creating a new storage with [`BlockStorage::create_with_options`].
+pub struct StorageOptions {
+  /// Number of blocks reserved for meta at the start of the file.
+  pub meta_blocks: u64,
+  /// Applies to the created handle only; storages are opened with [`GrowthPolicy::Exact`].
+  pub growth: GrowthPolicy,
+  /// Reserves this many bytes at the start of every block and starts the payload
+  /// after them, so payloads are aligned to it within the file, e.g. for mmap
+  /// readers. Must be 0 (no reservation) or a power of two below the block size.
+  /// Costs `payload_alignment` bytes of every claim.
+  pub payload_alignment: u64,
+  /// Applies to the created handle only; storages are opened with [`SyncPolicy::Never`].
+  pub sync_policy: SyncPolicy,
+  /// Applied to every block payload, see [`Transform`]. The storage must be opened
+  /// again with [`BlockStorage::open_with_transform`] and the same transform.
+  pub transform: Option<Box<dyn Transform>>,
+  /// Size of every block in bytes, a power of two of at least 512. Recorded in the
+  /// header, so opening always uses the size the file was created with.
+  pub block_size: u64,
+  /// Applies to the created handle only, see [`BlockStorage::set_io_timeout`].
+  pub io_timeout: Option<Duration>,
+  /// Byte offset of the storage in the file, leaving the bytes before it to other
+  /// data. Such a storage must be opened with [`BlockStorage::open_at`].
+  pub base_offset: u64,
+  /// Byte that unwritten parts of claimed blocks read back as, e.g. `0xFF` to tell
+  /// them apart from written zeros. Recorded in the header. Any other value than 0
+  /// costs writing it over every block the file grows by. Punched holes always read
+  /// back as zeros.
+  pub fill_byte: u8,
+}
+
+impl Default for StorageOptions {
+  fn default() -> StorageOptions {
+    StorageOptions {
+      meta_blocks: 1,
+      growth: GrowthPolicy::Exact,
+      payload_alignment: 0,
+      sync_policy: SyncPolicy::Never,
+      transform: None,
+      block_size: BLOCK_SIZE,
+      io_timeout: None,
+      base_offset: 0,
+      fill_byte: 0,
+    }
   }
 }
 
-struct BlockStorageMeta {
+struct BlockStorageMeta<A> {
   offset: u64,
+  meta_blocks: u64,
+  payload_alignment: u64,
+  transform: u64,
+  block_size: u64,
+  fill_byte: u8,
+  version: u64,
+  /// The [`VarintCodec::ID`] of the storage.
+  codec: u64,
+  allocator: A,
+  /// Opaque bytes stored behind the allocator, see [`BlockStorage::set_app_metadata`].
+  app_metadata: Vec<u8>,
+  /// Length of the header fields as currently stored on disk.
+  header_len: usize,
 }
 
-impl BlockStorageMeta {
+impl<A: Allocator> BlockStorageMeta<A> {
+  pub fn new(meta_blocks: u64, allocator: A) -> BlockStorageMeta<A> {
+    BlockStorageMeta {
+      offset: meta_blocks,
+      meta_blocks,
+      payload_alignment: 0,
+      transform: 0,
+      block_size: BLOCK_SIZE,
+      fill_byte: 0,
+      version: FORMAT_VERSION,
+      codec: LeaflessVarint::ID,
+      allocator,
+      app_metadata: Vec::new(),
+      header_len: 0,
+    }
+  }
+
+  /// Always in the built-in varints, so the header, including the codec of the
+  /// storage, can be read before the codec is known.
+  pub fn serialize_header(&self) -> VecDeque<u8> {
+    let mut buf = Encoder::encode_u64(self.offset);
+    buf.extend(Encoder::encode_u64(self.meta_blocks));
+    buf.extend(Encoder::encode_u64(self.payload_alignment));
+    buf.extend(Encoder::encode_u64(self.transform));
+    buf.extend(Encoder::encode_u64(self.block_size));
+    buf.extend(Encoder::encode_u64(self.fill_byte as u64));
+    buf.extend(Encoder::encode_u64(self.version));
+    buf.extend(Encoder::encode_u64(self.codec));
+    buf
+  }
+
+  /// The header, then the body of allocator state and app metadata as its length,
+  /// checksum and bytes.
   pub fn serialize(&self) -> VecDeque<u8> {
-    Encoder::encode_u64(self.offset)
+    let mut body = self.allocator.serialize();
+    body.extend(Encoder::encode_u64(self.app_metadata.len() as u64));
+    body.extend(&self.app_metadata);
+    let mut buf = self.serialize_header();
+    buf.extend(Encoder::encode_u64(body.len() as u64));
+    buf.extend(checksum::fnv1a64(body.make_contiguous()).to_le_bytes());
+    buf.extend(body);
+    buf
   }
 
-  pub fn deserialize(&mut self, data: &mut VecDeque<u8>) {
+  pub fn deserialize_header(&mut self, data: &mut VecDeque<u8>) {
+    let len = data.len();
     self.offset = Decoder::decode_u64(data);
+    // Files written before the meta reservation existed store nothing here.
+    self.meta_blocks = Decoder::decode_u64(data).max(1);
+    self.payload_alignment = Decoder::decode_u64(data);
+    self.transform = Decoder::decode_u64(data);
+    self.block_size = match Decoder::decode_u64(data) {
+      0 => BLOCK_SIZE,
+      block_size => block_size,
+    };
+    self.fill_byte = Decoder::decode_u64(data) as u8;
+    self.version = Decoder::decode_u64(data);
+    self.codec = Decoder::decode_u64(data);
+    self.header_len = len - data.len();
+  }
+
+  /// Fails with `InvalidData` if the allocator state or app metadata doesn't match
+  /// its checksum, before it is decoded.
+  pub fn deserialize(&mut self, data: &mut VecDeque<u8>) -> io::Result<()> {
+    self.deserialize_header(data);
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Meta checksum mismatch");
+    let len = Decoder::read_u64(data).map_err(|_| invalid())?;
+    let checksum = Decoder::decode_fixed::<8>(data).map_err(|_| invalid())?;
+    if len > data.len() as u64 {
+      return Err(invalid());
+    }
+    let mut body = data.drain(..len as usize).collect::<VecDeque<u8>>();
+    if checksum::fnv1a64(body.make_contiguous()) != u64::from_le_bytes(checksum) {
+      return Err(invalid());
+    }
+    self.allocator.deserialize(&mut body);
+    let app_len = Decoder::decode_u64(&mut body).min(body.len() as u64);
+    self.app_metadata = body.drain(..app_len as usize).collect();
+    Ok(())
   }
 }
 
-pub struct BlockStorage {
+/// Blocks of a file, with freed blocks managed by `A` and the varints of tagged
+/// blocks and records written in the scheme `C`, which the header records.
+pub struct BlockStorage<A: Allocator = FreeList, C: VarintCodec = LeaflessVarint> {
   file: fs::File,
-  meta: BlockStorageMeta,
+  meta: BlockStorageMeta<A>,
+  metrics: Metrics,
+  growth: GrowthPolicy,
+  /// Number of blocks the file currently holds, claimed or not.
+  capacity: u64,
+  sync_policy: SyncPolicy,
+  unsynced_writes: u64,
+  last_sync: Instant,
+  /// Shared with the views of [`BlockStorage::load_into_memory`].
+  transform: Option<Rc<dyn Transform>>,
+  /// Offset and count of the block most recently claimed or written.
+  last_block: Option<(u64, u64)>,
+  io_timeout: Option<Duration>,
+  paranoid: bool,
+  /// File position of the meta block; all block positions are relative to it.
+  base_offset: u64,
+  checksum_failure_handler: Option<ChecksumFailureHandler>,
+  corruption_handler: Option<CorruptionHandler>,
+  /// Holds the meta of a raw storage instead of its first blocks.
+  meta_file: Option<fs::File>,
+  checkpoints: u64,
+  /// Runs the compaction policy against the allocator after every free.
+  compaction_check: Option<CompactionCheck<A>>,
+  /// Set by [`BlockStorage::open_read_only`].
+  read_only: bool,
+  /// Cleared buffers for reads and writes to reuse, at most `pool_capacity`.
+  buffer_pool: Vec<Vec<u8>>,
+  pool_capacity: usize,
+  /// Position of the file cursor, if known.
+  cursor: Option<u64>,
+  /// Length of the file as last set, written or seen by a read ending early. A
+  /// file truncated behind the storage's back is only noticed by such a read.
+  file_len: u64,
+  codec: PhantomData<C>,
 }
 
 impl BlockStorage {
   pub fn create(file: fs::File) -> io::Result<BlockStorage> {
-    let mut storage = BlockStorage {
-      file: file,
-      meta: BlockStorageMeta { offset: 1 },
+    BlockStorage::create_with_options(file, StorageOptions::default())
+  }
+
+  pub fn create_with_options(file: fs::File, options: StorageOptions) -> io::Result<BlockStorage> {
+    BlockStorage::create_with_allocator(file, options, FreeList::new())
+  }
+
+  /// Creates a storage in a new file at `path`, replacing any existing file, and
+  /// syncs the directory so the file itself survives a crash.
+  pub fn create_path<P: AsRef<Path>>(path: P) -> io::Result<BlockStorage> {
+    let path = path.as_ref();
+    let file = fs::File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(path)?;
+    let storage = BlockStorage::create(file)?;
+    storage.file.sync_all()?;
+    atomic::sync_dir(path)?;
+    Ok(storage)
+  }
+
+  pub fn create_with_sync_policy(file: fs::File, policy: SyncPolicy) -> io::Result<BlockStorage> {
+    let options = StorageOptions {
+      sync_policy: policy,
+      ..StorageOptions::default()
     };
+    BlockStorage::create_with_options(file, options)
+  }
+
+  pub fn open(file: fs::File) -> io::Result<BlockStorage> {
+    BlockStorage::open_with_allocator(file, FreeList::new())
+  }
+
+  /// Opens a storage for reading only, e.g. from a file opened without write access.
+  ///
+  /// Takes a shared lock instead of an exclusive one, so several read-only handles
+  /// can share the file while opening it for writing fails with
+  /// [`LeaflessError::Locked`], and the other way round. Anything that would write
+  /// to the file, such as claiming, freeing or writing blocks, fails with
+  /// `PermissionDenied`.
+  pub fn open_read_only(file: fs::File) -> io::Result<BlockStorage> {
+    BlockStorage::open_inner(file, FreeList::new(), None, 0, true)
+  }
+
+  /// Opens a storage like [`BlockStorage::open`], failing with `InvalidData` if it
+  /// was created with a block size other than `expected_block_size`.
+  pub fn open_expecting(file: fs::File, expected_block_size: u64) -> io::Result<BlockStorage> {
+    let storage = BlockStorage::open(file)?;
+    if storage.meta.block_size != expected_block_size {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "Storage has block size {}, expected {}",
+          storage.meta.block_size, expected_block_size
+        ),
+      ));
+    }
+    Ok(storage)
+  }
+
+  /// Opens a storage created with `transform`, failing with `InvalidData` if the
+  /// header records a different one.
+  pub fn open_with_transform(
+    file: fs::File,
+    transform: Box<dyn Transform>,
+  ) -> io::Result<BlockStorage> {
+    BlockStorage::open_inner(file, FreeList::new(), Some(transform), 0, false)
+  }
+
+  /// Opens a storage embedded in `file` at `base_offset`, created with
+  /// [`StorageOptions::base_offset`].
+  pub fn open_at(file: fs::File, base_offset: u64) -> io::Result<BlockStorage> {
+    BlockStorage::open_inner(file, FreeList::new(), None, base_offset, false)
+  }
+
+  /// Creates a raw storage, whose meta lives in `meta_file` instead of at the start
+  /// of `file`, so every block of `file` is usable, starting at block 0.
+  ///
+  /// `file` then holds nothing but block data and isn't self-describing: it can only
+  /// be opened again with [`BlockStorage::open_raw`] and the same `meta_file`.
+  pub fn create_raw(file: fs::File, meta_file: fs::File) -> io::Result<BlockStorage> {
+    let mut storage = BlockStorage::with_meta(file, BlockStorageMeta::new(0, FreeList::new()))?;
+    storage.meta_file = Some(meta_file);
+    storage.capacity = storage.fileBlocks()?;
     storage.flushMeta()?;
     Ok(storage)
   }
 
-  pub fn open(file: fs::File) -> io::Result<BlockStorage> {
-    let mut storage = BlockStorage {
-      file: file,
-      meta: BlockStorageMeta { offset: 0 },
+  /// Opens a storage created with [`BlockStorage::create_raw`].
+  pub fn open_raw(file: fs::File, mut meta_file: fs::File) -> io::Result<BlockStorage> {
+    let mut meta = Vec::new();
+    meta_file.read_to_end(&mut meta)?;
+    let mut storage = BlockStorage::with_meta(file, BlockStorageMeta::new(0, FreeList::new()))?;
+    storage.meta.deserialize(&mut VecDeque::from(meta))?;
+    storage.checkFormat()?;
+    // Decoding reads a stored 0 as the single meta block of older files.
+    storage.meta.meta_blocks = 0;
+    storage.meta_file = Some(meta_file);
+    storage.capacity = storage.fileBlocks()?.max(storage.meta.offset);
+    Ok(storage)
+  }
+
+  /// Rebuilds the meta of a storage whose meta is lost or corrupt, scanning its
+  /// blocks for the free list and claimed offset.
+  ///
+  /// The layout recorded in the lost meta can't be recovered, so `options` must
+  /// give the one the storage was created with: meta blocks, block size, payload
+  /// alignment, transform, base offset and fill byte. Application metadata is lost.
+  ///
+  /// A block starting a [`BlockStorage::writeTaggedBlock`] payload with a valid
+  /// checksum is claimed together with the blocks the payload spans. Other blocks
+  /// are claimed unless they hold only the fill byte, which unwritten blocks do;
+  /// those are freed and the claimed offset ends behind the last claimed block.
+  /// Freed blocks that still hold data are leaked rather than handed out twice,
+  /// while a claimed block that was never written may be handed out again. Tagged
+  /// payloads through a transform aren't recognized and claimed block by block.
+  pub fn repair(file: fs::File, options: StorageOptions) -> io::Result<BlockStorage> {
+    let mut storage = BlockStorage::with_options(file, options, FreeList::new())?;
+    let meta_blocks = storage.meta.meta_blocks;
+    storage.capacity = storage.fileBlocks()?.max(meta_blocks);
+    let mut unused = Vec::new();
+    let mut end = meta_blocks;
+    let mut offset = meta_blocks;
+    while offset < storage.capacity {
+      match storage.scanned_span(offset)? {
+        Some(count) => {
+          offset += count;
+          end = offset;
+        }
+        None => {
+          unused.push(offset);
+          offset += 1;
+        }
+      }
+    }
+    storage.meta.offset = end;
+    for offset in unused.into_iter().take_while(|&offset| offset < end) {
+      storage.meta.allocator.deallocate(offset, 1);
+    }
+    storage.flushMeta()?;
+    Ok(storage)
+  }
+
+  /// Creates a storage in `file` from a stream written by [`BlockStorage::stream_to`].
+  pub fn load_from<R: Read>(file: fs::File, input: &mut R) -> io::Result<BlockStorage> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let meta_len = read_stream_u64(input)?;
+    let meta = read_stream_bytes(input, meta_len)?;
+    let mut storage = BlockStorage::with_meta(file, BlockStorageMeta::new(1, FreeList::new()))?;
+    storage.meta.deserialize(&mut VecDeque::from(meta))?;
+    storage.checkFormat()?;
+    let block_size = storage.meta.block_size;
+    let meta_fits = storage
+      .meta
+      .meta_blocks
+      .checked_mul(block_size)
+      .is_some_and(|meta_end| meta_len <= meta_end);
+    let end = match storage.meta.offset.checked_mul(block_size) {
+      Some(end) if meta_fits => end,
+      _ => return Err(invalid("Streamed meta is corrupt")),
     };
-    let mut header = storage.readData(0, BLOCK_SIZE)?;
-    storage.meta.deserialize(&mut header);
+    // The whole meta is rewritten, not only the header.
+    storage.meta.header_len = 0;
+    storage.capacity = storage.meta.offset;
+    storage.setFileLen(end)?;
+    storage.flushMeta()?;
+    // Free blocks aren't streamed, and the resize fills them with zeros.
+    let free = storage.meta.allocator.extents().collect::<Vec<_>>();
+    for (offset, count) in free {
+      storage.fillBlocks(offset, offset + count)?;
+    }
+    for _ in 0..read_stream_u64(input)? {
+      let offset = read_stream_u64(input)?;
+      let len = read_stream_u64(input)?;
+      let in_range = offset >= storage.meta.meta_blocks
+        && offset
+          .checked_mul(block_size)
+          .and_then(|position| position.checked_add(len))
+          .is_some_and(|record_end| record_end <= end);
+      if !in_range {
+        return Err(invalid("Streamed record exceeds the claimed blocks"));
+      }
+      let data = read_stream_bytes(input, len)?;
+      storage.writeFlush(offset * block_size, &data)?;
+    }
     Ok(storage)
   }
 
-  fn flushMeta(&mut self) -> io::Result<()> {
-    self.writeFlush(0, &Vec::from(self.meta.serialize()).as_mut_slice())
+  /// Reads only the header of the storage file at `path` without opening it as a storage.
+  pub fn read_header<P: AsRef<Path>>(path: P) -> io::Result<Header> {
+    let file = fs::File::open(path)?;
+    let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+    file.take(HEADER_SIZE).read_to_end(&mut buf)?;
+    let mut meta = BlockStorageMeta::new(1, FreeList::new());
+    meta.deserialize_header(&mut VecDeque::from(buf));
+    Ok(Header {
+      block_size: meta.block_size,
+      block_count: meta.offset,
+      meta_blocks: meta.meta_blocks,
+      payload_alignment: meta.payload_alignment,
+      transform: meta.transform,
+      codec: meta.codec,
+    })
   }
+}
 
-  fn writeFlush(&mut self, position: u64, data: &[u8]) -> io::Result<()> {
-    self.file.seek(io::SeekFrom::Start(position))?;
-    self.file.write_all(data)?;
-    self.file.flush()
+impl<C: VarintCodec> BlockStorage<FreeList, C> {
+  /// Number of blocks starting at `offset` that [`BlockStorage::repair`] takes as
+  /// claimed, `None` if the block is unwritten.
+  fn scanned_span(&mut self, offset: u64) -> io::Result<Option<u64>> {
+    let block = self.dataBlock(offset, 1);
+    let data = self.readBlockOffset(&block, 0, block.payload_size())?;
+    let count = tagged_len::<C>(&data)
+      .filter(|_| self.transform.is_none())
+      .and_then(|len| Some((len, len.checked_add(self.meta.payload_alignment)?)))
+      .map(|(len, stored)| (len, stored.div_ceil(self.meta.block_size)));
+    if let Some((len, count)) = count {
+      if count <= self.capacity - offset {
+        let span = self.dataBlock(offset, count);
+        let payload = self.readBlockOffset(&span, 0, len)?;
+        if parse_tagged::<C>(payload).is_ok() {
+          return Ok(Some(count));
+        }
+      }
+    }
+    let fill = self.meta.fill_byte;
+    Ok(data.iter().any(|&byte| byte != fill).then_some(1))
   }
 
-  fn readData(&mut self, position: u64, max_length: u64) -> io::Result<VecDeque<u8>> {
-    self.file.seek(io::SeekFrom::Start(position))?;
-    let mut buf = Vec::<u8>::with_capacity(max_length as usize);
-    buf.resize(max_length as usize, 0);
-    let mut total_read: u64 = 0;
-    loop {
-      let slice = &mut buf[(total_read as usize)..];
-      let read = self.file.read(slice)?;
-      total_read += read as u64;
-      if read == 0 || total_read == max_length {
-        break;
+  /// Ratio of free extents to free blocks: 1.0 if every free block is its own
+  /// extent, close to 0 if they form one large extent, 0 without free blocks.
+  pub fn fragmentation(&self) -> f64 {
+    fragmentation(&self.meta.allocator)
+  }
+
+  /// Calls `callback` with the fragmentation and free block count whenever a free
+  /// leaves at least `policy.min_free_blocks` free blocks fragmented above
+  /// `policy.fragmentation_threshold`, after being below either before. `None`
+  /// removes the policy.
+  ///
+  /// The callback runs once the free is complete but can't reach the storage, so
+  /// compaction itself, e.g. [`BlockStorage::compact_into`], is left to the caller
+  /// between operations.
+  pub fn set_compaction_policy(&mut self, policy: Option<(CompactionPolicy, CompactionCallback)>) {
+    self.compaction_check = policy.map(|(policy, mut callback)| {
+      let mut triggered = false;
+      Box::new(move |list: &FreeList| {
+        let fragmentation = fragmentation(list);
+        let free_blocks = list.free_blocks();
+        let due =
+          free_blocks >= policy.min_free_blocks && fragmentation > policy.fragmentation_threshold;
+        if due && !triggered {
+          callback(fragmentation, free_blocks);
+        }
+        triggered = due;
+      }) as CompactionCheck<FreeList>
+    });
+  }
+
+  /// Moves the data of `block` to the free blocks starting at `to_offset`, frees
+  /// its old location and returns the handle to the new one. Fails with
+  /// `InvalidInput` and changes nothing if `block` isn't claimed or the
+  /// destination isn't free.
+  pub fn relocate_block(&mut self, block: DataBlock, to_offset: u64) -> io::Result<DataBlock> {
+    self.ensureClaimed(&block)?;
+    let block_size = self.meta.block_size;
+    let count = block.size / block_size;
+    if !self.meta.allocator.take(to_offset, count) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Destination blocks are not free",
+      ));
+    }
+    // The meta is only written once the data is copied, so a failure leaves the
+    // block where it was.
+    let copied = self
+      .readData(block.offset * block_size, block.size)
+      .and_then(|mut data| self.writeFlush(to_offset * block_size, data.make_contiguous()));
+    if let Err(err) = copied.and_then(|()| self.freeBlock(block)) {
+      self.meta.allocator.deallocate(to_offset, count);
+      return Err(err);
+    }
+    Ok(self.dataBlock(to_offset, count))
+  }
+
+  /// Writes all claimed blocks densely into a new storage in `dest` and returns
+  /// where each of them went, so references can be rewritten.
+  ///
+  /// Unlike [`BlockStorage::relocate_block`] this leaves the storage untouched,
+  /// so an interrupted compaction only leaves an unused `dest`. Blocks are copied
+  /// as stored; a storage with a [`Transform`] is compacted into one expecting it.
+  pub fn compact_into(&mut self, dest: fs::File) -> io::Result<RemapTable> {
+    let block_size = self.meta.block_size;
+    let options = StorageOptions {
+      meta_blocks: self.meta.meta_blocks,
+      payload_alignment: self.meta.payload_alignment,
+      block_size,
+      fill_byte: self.meta.fill_byte,
+      ..StorageOptions::default()
+    };
+    let mut compacted =
+      BlockStorage::<FreeList, C>::create_with_allocator(dest, options, FreeList::new())?;
+    compacted.meta.transform = self.meta.transform;
+    compacted.meta.app_metadata = self.meta.app_metadata.clone();
+    let runs = self
+      .iter_blocks_by_offset()
+      .map(|run| (run.offset, run.size / block_size))
+      .collect::<Vec<_>>();
+    let mut remap = RemapTable::default();
+    for (offset, count) in runs {
+      let target = compacted.claimBlock(count)?.offset;
+      for chunk in (0..count).step_by(STREAM_CHUNK_BLOCKS as usize) {
+        let len = (count - chunk).min(STREAM_CHUNK_BLOCKS) * block_size;
+        let mut data = self.readData((offset + chunk) * block_size, len)?;
+        compacted.writeFlush((target + chunk) * block_size, data.make_contiguous())?;
       }
+      remap.runs.push((offset, target, count));
     }
-    Ok(buf.into())
+    compacted.flushMeta()?;
+    compacted.file.sync_all()?;
+    Ok(remap)
   }
 
-  fn claimBlock(&mut self, count: u64) -> io::Result<DataBlock> {
-    self.meta.offset += count;
-    self.file.set_len(self.meta.offset * BLOCK_SIZE)?;
-    self.flushMeta()?;
-    Ok(DataBlock {
-      offset: self.meta.offset - count,
-      size: count * BLOCK_SIZE,
+  /// Writes the meta and all claimed blocks to `out`, skipping free space, and
+  /// returns the number of bytes written. [`BlockStorage::load_from`] restores it.
+  ///
+  /// The stream is the meta length as a varint and the meta, then the record count
+  /// and records of block offset, length and bytes, each at most 64 blocks long.
+  /// Blocks are copied as stored, so the stream of a storage with a [`Transform`]
+  /// needs the same transform to be read.
+  pub fn stream_to<W: Write>(&mut self, out: &mut W) -> io::Result<u64> {
+    let block_size = self.meta.block_size;
+    let mut chunks = Vec::new();
+    for run in self.iter_blocks_by_offset() {
+      let end = run.offset + run.size / block_size;
+      for offset in (run.offset..end).step_by(STREAM_CHUNK_BLOCKS as usize) {
+        chunks.push((offset, (end - offset).min(STREAM_CHUNK_BLOCKS)));
+      }
+    }
+    let meta = self.meta.serialize();
+    let mut buf = Encoder::encode_u64(meta.len() as u64);
+    buf.extend(meta);
+    buf.extend(Encoder::encode_u64(chunks.len() as u64));
+    out.write_all(buf.make_contiguous())?;
+    let mut written = buf.len() as u64;
+    for (offset, count) in chunks {
+      let data = self.readData(offset * block_size, count * block_size)?;
+      let mut record = Encoder::encode_u64(offset);
+      record.extend(Encoder::encode_u64(data.len() as u64));
+      record.extend(data);
+      out.write_all(record.make_contiguous())?;
+      written += record.len() as u64;
+    }
+    Ok(written)
+  }
+
+  /// Returns the claimed regions in ascending offset order, for sequential scans.
+  ///
+  /// Claims are not recorded individually, so each handle covers a maximal run of
+  /// claimed blocks between free ones, which may span several claims.
+  pub fn iter_blocks_by_offset(&self) -> impl Iterator<Item = DataBlock> + '_ {
+    let mut start = self.meta.meta_blocks;
+    let mut free = self.meta.allocator.extents();
+    let end = self.meta.offset;
+    std::iter::from_fn(move || loop {
+      if start >= end {
+        return None;
+      }
+      let (run_end, next) = match free.next() {
+        Some((offset, count)) => (offset, offset + count),
+        None => (end, end),
+      };
+      let run_start = start;
+      start = next;
+      if run_end > run_start {
+        return Some(self.dataBlock(run_start, run_end - run_start));
+      }
     })
   }
 
-  pub fn writeBlockOffset(
-    &mut self,
-    block: &DataBlock,
-    offset: u64,
-    data: VecDeque<u8>,
-  ) -> io::Result<()> {
-    if data.len() as u64 + offset > block.size {
-      Err(io::Error::new(
-        io::ErrorKind::InvalidData,
-        "Size exceeds block size",
-      ))
-    } else {
-      self.writeFlush(
-        block.offset * BLOCK_SIZE + offset,
-        Vec::from(data).as_slice(),
-      )
+  /// Frees every claimed region for which `keep` returns false, e.g. to sweep
+  /// unreachable blocks.
+  ///
+  /// Regions are not claims: claims aren't recorded individually, so `keep` is
+  /// called with the maximal runs of [`BlockStorage::iter_blocks_by_offset`], and
+  /// neighbouring claims are kept or freed together. A single claim inside a run
+  /// can only be freed through its own handle with [`BlockStorage::freeBlock`].
+  pub fn retain<F: FnMut(&DataBlock) -> bool>(&mut self, mut keep: F) -> io::Result<()> {
+    let swept = self
+      .iter_blocks_by_offset()
+      .filter(|block| !keep(block))
+      .collect::<Vec<_>>();
+    for block in swept {
+      self.freeBlock(block)?;
     }
+    Ok(())
   }
 
-  pub fn writeBlock(&mut self, block: &DataBlock, data: VecDeque<u8>) -> io::Result<()> {
-    self.writeBlockOffset(block, 0, data)
+  /// Compares the claimed blocks of both storages, one block at a time, and
+  /// returns the offsets of those that differ in ascending order.
+  ///
+  /// Blocks are compared as stored, with unwritten space reading as zeros, so a
+  /// block only partially written in one storage equals its zero-padded copy.
+  pub fn diff(&mut self, other: &mut BlockStorage<FreeList, C>) -> io::Result<Vec<BlockDiff>> {
+    let block_size = self.meta.block_size;
+    if other.meta.block_size != block_size {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Storages have different block sizes",
+      ));
+    }
+    let mut diffs = Vec::new();
+    let start = self.meta.meta_blocks.min(other.meta.meta_blocks);
+    for offset in start..self.meta.offset.max(other.meta.offset) {
+      match (self.is_claimed(offset), other.is_claimed(offset)) {
+        (true, true) => {
+          let mut ours = Vec::from(self.readData(offset * block_size, block_size)?);
+          let mut theirs = Vec::from(other.readData(offset * block_size, block_size)?);
+          ours.resize(block_size as usize, 0);
+          theirs.resize(block_size as usize, 0);
+          if ours != theirs {
+            diffs.push(BlockDiff::Changed(offset));
+          }
+        }
+        (true, false) => diffs.push(BlockDiff::OnlyInSelf(offset)),
+        (false, true) => diffs.push(BlockDiff::OnlyInOther(offset)),
+        (false, false) => {}
+      }
+    }
+    Ok(diffs)
   }
 
-  pub fn readBlockOffset(
-    &mut self,
-    block: &DataBlock,
-    offset: u64,
-    max_length: u64,
-  ) -> io::Result<VecDeque<u8>> {
-    self.readData(block.offset * BLOCK_SIZE + offset, max_length)
+  fn is_claimed(&self, offset: u64) -> bool {
+    offset >= self.meta.meta_blocks
+      && offset < self.meta.offset
+      && !self.meta.allocator.overlaps_free(offset, 1)
   }
 
-  pub fn readBlock(&mut self, block: &DataBlock) -> io::Result<VecDeque<u8>> {
-    self.readBlockOffset(block, 0, block.size)
+  /// Returns the claimed regions beyond the end of the claimed blocks at
+  /// `checkpoint`, in ascending offset order.
+  ///
+  /// Only blocks that extended the storage are covered; a claim reusing freed
+  /// blocks from before the checkpoint, or a rewrite of an older block, is not.
+  pub fn blocks_since(&self, checkpoint: Checkpoint) -> Vec<DataBlock> {
+    self
+      .iter_blocks_by_offset()
+      .filter_map(|run| {
+        let end = run.offset + run.size / self.meta.block_size;
+        let start = run.offset.max(checkpoint.offset);
+        (start < end).then(|| self.dataBlock(start, end - start))
+      })
+      .collect()
+  }
+
+  /// Releases free list memory left over from a burst of frees that has since
+  /// been coalesced or claimed. The free blocks themselves are unchanged.
+  pub fn shrink_free_list(&mut self) {
+    self.meta.allocator.shrink_to_fit();
   }
 }
 
-#[cfg(test)]
-mod tests {
-  use rand::{self, Rng};
-  use std::collections::VecDeque;
-  use std::env::temp_dir;
-  use std::fs;
-  use super::BLOCK_SIZE;
-  use super::BlockStorage;
+#[allow(non_snake_case)]
+impl<A: Allocator, C: VarintCodec> BlockStorage<A, C> {
+  /// Creates a storage whose freed blocks are managed by `allocator`.
+  pub fn create_with_allocator(
+    file: fs::File,
+    options: StorageOptions,
+    allocator: A,
+  ) -> io::Result<BlockStorage<A, C>> {
+    let mut storage = BlockStorage::with_options(file, options, allocator)?;
+    storage.capacity = storage.fileBlocks()?.max(storage.meta.offset);
+    storage.flushMeta()?;
+    Ok(storage)
+  }
 
-  fn create_temp_file_name() -> std::path::PathBuf {
-    let temp_file_name: String = rand::thread_rng()
-      .sample_iter(&rand::distributions::Alphanumeric)
-      .take(16)
-      .map(char::from)
-      .collect();
+  /// Validates `options` and sets up a storage laid out by them, without touching
+  /// the file.
+  fn with_options(
+    file: fs::File,
+    options: StorageOptions,
+    allocator: A,
+  ) -> io::Result<BlockStorage<A, C>> {
+    if options.meta_blocks == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "At least one meta block must be reserved",
+      ));
+    }
+    let block_size = options.block_size;
+    if !block_size.is_power_of_two() || block_size < MIN_BLOCK_SIZE {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block size must be a power of two of at least 512",
+      ));
+    }
+    let alignment = options.payload_alignment;
+    if alignment != 0 && (!alignment.is_power_of_two() || alignment >= block_size) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Payload alignment must be a power of two below the block size",
+      ));
+    }
+    if options.transform.as_ref().is_some_and(|t| t.id() == 0) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Transform id 0 is reserved",
+      ));
+    }
+    let mut storage =
+      BlockStorage::with_meta(file, BlockStorageMeta::new(options.meta_blocks, allocator))?;
+    storage.meta.payload_alignment = alignment;
+    storage.meta.block_size = block_size;
+    storage.meta.fill_byte = options.fill_byte;
+    storage.meta.transform = options.transform.as_ref().map_or(0, |t| t.id());
+    storage.transform = options.transform.map(Rc::from);
+    storage.growth = options.growth;
+    storage.sync_policy = options.sync_policy;
+    storage.io_timeout = options.io_timeout;
+    storage.base_offset = options.base_offset;
+    Ok(storage)
+  }
 
-    temp_dir().join(temp_file_name + ".leafless")
+  /// Opens a storage created with the same kind of allocator as `allocator`,
+  /// restoring its persisted state into it.
+  pub fn open_with_allocator(file: fs::File, allocator: A) -> io::Result<BlockStorage<A, C>> {
+    BlockStorage::open_inner(file, allocator, None, 0, false)
   }
 
-  fn create_temp_storage() -> BlockStorage {
-    let mut options = fs::File::options();
-    let open = options.read(true).write(true).create(true);
-    BlockStorage::create(open.open(create_temp_file_name()).unwrap()).unwrap()
+  fn open_inner(
+    file: fs::File,
+    allocator: A,
+    transform: Option<Box<dyn Transform>>,
+    base_offset: u64,
+    read_only: bool,
+  ) -> io::Result<BlockStorage<A, C>> {
+    let meta = BlockStorageMeta::new(1, allocator);
+    let mut storage = BlockStorage::with_meta_locked(file, meta, read_only)?;
+    storage.base_offset = base_offset;
+    // The header tells how large the meta region is.
+    let mut header = storage.readData(0, HEADER_SIZE)?;
+    storage.meta.deserialize_header(&mut header);
+    storage.checkFormat()?;
+    let meta_len = storage
+      .meta
+      .meta_blocks
+      .saturating_mul(storage.meta.block_size);
+    let mut meta = storage.readData(0, meta_len)?;
+    storage.meta.deserialize(&mut meta)?;
+    if storage.meta.transform != transform.as_ref().map_or(0, |t| t.id()) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "Storage was created with transform {}",
+          storage.meta.transform
+        ),
+      ));
+    }
+    storage.transform = transform.map(Rc::from);
+    // Blocks past the claimed offset, e.g. left by a crash between growing the file
+    // and flushing the meta, are reused by the next claims.
+    storage.capacity = storage.fileBlocks()?.max(storage.meta.offset);
+    Ok(storage)
   }
 
+  /// Takes an exclusive advisory lock on `file`, held until the storage is dropped,
+  /// so a second handle to the same file fails with [`LeaflessError::Locked`].
+  fn with_meta(file: fs::File, meta: BlockStorageMeta<A>) -> io::Result<BlockStorage<A, C>> {
+    BlockStorage::with_meta_locked(file, meta, false)
+  }
 
-  #[test]
-  fn test_create_block_storage() {
-    let file_name = create_temp_file_name();
-    let mut options = fs::File::options();
-    let open = options.read(true).write(true).create(true);
-    BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
-    let storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
-    assert_eq!(storage.meta.offset, 1);
+  /// Like [`BlockStorage::with_meta`], but a `read_only` storage takes a shared lock
+  /// and refuses to write.
+  fn with_meta_locked(
+    file: fs::File,
+    mut meta: BlockStorageMeta<A>,
+    read_only: bool,
+  ) -> io::Result<BlockStorage<A, C>> {
+    // Opening overwrites this with the recorded codec, which is then checked.
+    meta.codec = C::ID;
+    let locked = match read_only {
+      true => file.try_lock_shared(),
+      false => file.try_lock(),
+    };
+    match locked {
+      Ok(()) => {}
+      Err(fs::TryLockError::WouldBlock) => return Err(LeaflessError::Locked.into()),
+      Err(fs::TryLockError::Error(err)) => return Err(err),
+    }
+    Ok(BlockStorage {
+      file,
+      meta,
+      metrics: Metrics::default(),
+      growth: GrowthPolicy::Exact,
+      capacity: 0,
+      sync_policy: SyncPolicy::Never,
+      unsynced_writes: 0,
+      last_sync: Instant::now(),
+      transform: None,
+      last_block: None,
+      io_timeout: None,
+      paranoid: false,
+      base_offset: 0,
+      checksum_failure_handler: None,
+      corruption_handler: None,
+      meta_file: None,
+      checkpoints: 0,
+      compaction_check: None,
+      read_only,
+      buffer_pool: Vec::new(),
+      pool_capacity: 0,
+      cursor: None,
+      file_len: 0,
+      codec: PhantomData,
+    })
   }
 
-  #[test]
-  fn test_claim_storage() {
-    let mut storage = create_temp_storage();
-    let block = storage.claimBlock(1).unwrap();
-    let data = "data"
-      .chars()
-      .into_iter()
-      .map(|c| c as u8)
-      .collect::<VecDeque<_>>();
-    storage.writeBlock(&block, data.clone()).unwrap();
-    let mut read = storage.readBlock(&block).unwrap();
-    read.resize(4, 0);
-    assert_eq!(read, data);
-    read.resize(BLOCK_SIZE as usize + 1, 0);
-    assert!(storage.writeBlock(&block, read).is_err());
+  /// Fails with `InvalidData` if the deserialized header was written in another
+  /// format version or with a varint codec other than `C`, or holds a layout no
+  /// storage can be created with. The checksum only covers the meta body.
+  fn checkFormat(&self) -> io::Result<()> {
+    if self.meta.version != FORMAT_VERSION {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unsupported format version {}", self.meta.version),
+      ));
+    }
+    if self.meta.codec != C::ID {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Storage was created with varint codec {}", self.meta.codec),
+      ));
+    }
+    let block_size = self.meta.block_size;
+    let alignment = self.meta.payload_alignment;
+    if !block_size.is_power_of_two()
+      || block_size < MIN_BLOCK_SIZE
+      || (alignment != 0 && (!alignment.is_power_of_two() || alignment >= block_size))
+    {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Header holds an invalid layout: block size {block_size}, alignment {alignment}"),
+      ));
+    }
+    Ok(())
+  }
+
+  /// Whole blocks in the file. A partial trailing block, e.g. left by an
+  /// interrupted resize, isn't counted, so the next claim grows the file over it
+  /// instead of handing out a block writes to would fail.
+  fn fileBlocks(&mut self) -> io::Result<u64> {
+    self.file_len = self.file.metadata()?.len();
+    Ok(self.file_len.saturating_sub(self.base_offset) / self.meta.block_size)
+  }
+
+  fn setFileLen(&mut self, len: u64) -> io::Result<()> {
+    self.file.set_len(len)?;
+    self.file_len = len;
+    Ok(())
+  }
+
+  /// Returns the I/O counters accumulated since the storage was created or opened.
+  pub fn metrics(&self) -> Metrics {
+    self.metrics
+  }
+
+  /// Reads every write back and fails with `InvalidData` if it doesn't match, to
+  /// catch I/O bugs where they happen. Doubles the I/O, meant for debugging only.
+  pub fn with_paranoid_checks(mut self, enabled: bool) -> BlockStorage<A, C> {
+    self.paranoid = enabled;
+    self
+  }
+
+  /// Keeps up to `capacity` buffers handed back through
+  /// [`BlockStorage::recycle_buffer`] or consumed by writes, and reads into them
+  /// instead of allocating, e.g. for hot loops over many blocks. 0, the default,
+  /// disables pooling.
+  pub fn with_buffer_pool(mut self, capacity: usize) -> BlockStorage<A, C> {
+    self.pool_capacity = capacity;
+    self.buffer_pool.truncate(capacity);
+    self
+  }
+
+  /// Returns an empty buffer, from the pool if it holds one.
+  pub fn take_buffer(&mut self) -> VecDeque<u8> {
+    self.take_pooled().into()
+  }
+
+  /// Hands `buf` back to the pool of [`BlockStorage::with_buffer_pool`], or drops
+  /// it if the pool is full. It is cleared first, so no data leaks into later reads.
+  pub fn recycle_buffer(&mut self, buf: VecDeque<u8>) {
+    self.recycle(buf.into());
+  }
+
+  fn take_pooled(&mut self) -> Vec<u8> {
+    self.buffer_pool.pop().unwrap_or_default()
+  }
+
+  fn recycle(&mut self, mut buf: Vec<u8>) {
+    if self.buffer_pool.len() < self.pool_capacity && buf.capacity() > 0 {
+      buf.clear();
+      self.buffer_pool.push(buf);
+    }
+  }
+
+  /// Fails reads and writes that take longer than `timeout` with `TimedOut`, e.g.
+  /// for files on a network file system that may hang. `None` disables it.
+  ///
+  /// Every timed operation runs on its own thread, which costs a thread spawn per
+  /// read or write. A timed out operation is abandoned, not cancelled, and may
+  /// still complete in the background. Timed operations use positional I/O, so a
+  /// late one still only touches the bytes it was asked to, and on Unix never
+  /// moves the file cursor later operations rely on.
+  pub fn set_io_timeout(&mut self, timeout: Option<Duration>) {
+    self.io_timeout = timeout;
+  }
+
+  /// Persists `bytes` in the meta region, e.g. an application's schema version,
+  /// replacing any previously set.
+  ///
+  /// The bytes share the meta region with the free list and fail with
+  /// `InvalidInput` if both don't fit, leaving the previous bytes in place; a
+  /// storage expecting large metadata reserves more [`StorageOptions::meta_blocks`].
+  pub fn set_app_metadata(&mut self, bytes: &[u8]) -> io::Result<()> {
+    let previous = std::mem::replace(&mut self.meta.app_metadata, bytes.to_vec());
+    let fits = self.meta_file.is_some()
+      || self.meta.serialize().len() as u64 <= self.meta.meta_blocks * self.meta.block_size;
+    if !fits {
+      self.meta.app_metadata = previous;
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "App metadata exceeds the meta region",
+      ));
+    }
+    // The app metadata follows the free list, so the whole meta is rewritten.
+    self.meta.header_len = 0;
+    self.flushMeta()
+  }
+
+  /// The bytes last stored with [`BlockStorage::set_app_metadata`], empty if none.
+  pub fn app_metadata(&self) -> Vec<u8> {
+    self.meta.app_metadata.clone()
+  }
+
+  /// Sets the end of the claimed blocks to `offset` and flushes the meta, e.g. for
+  /// migration tools rewriting the meta of a file whose blocks stay in place. The
+  /// file is extended if it ends before `offset`.
+  ///
+  /// Fails with `InvalidInput` if `offset` lies inside the meta region.
+  ///
+  /// Nothing checks `offset` against the blocks in use or the free list. A lower
+  /// offset hands claimed blocks out again and one below a free extent corrupts the
+  /// free list; either corrupts the store, so this is for tools that know the
+  /// layout better than the meta does.
+  pub fn set_meta_offset_raw(&mut self, offset: u64) -> io::Result<()> {
+    self.ensureWritable()?;
+    if offset < self.meta.meta_blocks {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Offset lies inside the meta region",
+      ));
+    }
+    if offset > self.capacity {
+      self.setFileLen(self.base_offset + offset * self.meta.block_size)?;
+      self.metrics.resizes += 1;
+      self.fillBlocks(self.capacity, offset)?;
+      self.capacity = offset;
+    }
+    self.meta.offset = offset;
+    self.flushMeta()
+  }
+
+  /// Persists the current meta block without writing any data block.
+  pub fn sync_meta(&mut self) -> io::Result<()> {
+    self.flushMeta()
+  }
+
+  fn flushMeta(&mut self) -> io::Result<()> {
+    let _written = self.writeMeta()?;
+    trace!(TRACE, bytes = _written, "flushed meta");
+    Ok(())
+  }
+
+  /// Writes the changed part of the meta and returns the number of bytes written.
+  ///
+  /// The header is always rewritten, the free list behind it only if it changed or
+  /// got shifted, so claims that just bump the offset stay O(1).
+  fn writeMeta(&mut self) -> io::Result<usize> {
+    self.ensureWritable()?;
+    if let Some(meta_file) = &mut self.meta_file {
+      let meta = Vec::from(self.meta.serialize());
+      meta_file.seek(io::SeekFrom::Start(0))?;
+      write_flush_retrying(meta_file, &meta)?;
+      meta_file.set_len(meta.len() as u64)?;
+      self.meta.allocator.mark_clean();
+      return Ok(meta.len());
+    }
+    let mut meta = self.meta.serialize_header();
+    let header_len = meta.len();
+    let write_free_list = self.meta.allocator.is_dirty() || header_len != self.meta.header_len;
+    if write_free_list {
+      meta = self.meta.serialize();
+      if meta.len() as u64 > self.meta.meta_blocks * self.meta.block_size {
+        return Err(io::Error::other("Meta exceeds meta block size"));
+      }
+    }
+    let meta = Vec::from(meta);
+    self.writeFlush(0, meta.as_slice())?;
+    self.meta.header_len = header_len;
+    if write_free_list {
+      self.meta.allocator.mark_clean();
+    }
+    Ok(meta.len())
+  }
+
+  /// Fails with `PermissionDenied` if the storage was opened read-only.
+  fn ensureWritable(&self) -> io::Result<()> {
+    if self.read_only {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Storage is opened read-only",
+      ));
+    }
+    Ok(())
+  }
+
+  fn writeFlush(&mut self, position: u64, data: &[u8]) -> io::Result<()> {
+    self.ensureWritable()?;
+    self.metrics.writes += 1;
+    self.metrics.flushes += 1;
+    let file_position = self.base_offset + position;
+    match self.io_timeout {
+      None => {
+        self.seekTo(file_position)?;
+        self.cursor = None;
+        write_flush_retrying(&mut self.file, data)?;
+        self.cursor = Some(file_position + data.len() as u64);
+      }
+      Some(timeout) => {
+        // Positional writes move the cursor on Windows.
+        self.cursor = None;
+        let mut file = PositionedFile::new(&self.file, file_position)?;
+        let data = data.to_vec();
+        with_timeout(timeout, move || write_flush_retrying(&mut file, &data))?
+      }
+    }
+    self.file_len = self.file_len.max(file_position + data.len() as u64);
+    self.metrics.bytes_written += data.len() as u64;
+    if self.paranoid && self.readData(position, data.len() as u64)? != data {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Data read back at {} differs from the write", position),
+      ));
+    }
+    self.unsynced_writes += 1;
+    self.syncIfDue()
+  }
+
+  fn syncIfDue(&mut self) -> io::Result<()> {
+    let due = match self.sync_policy {
+      SyncPolicy::Never => false,
+      SyncPolicy::Always => true,
+      SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+      SyncPolicy::Writes(writes) => self.unsynced_writes >= writes,
+      SyncPolicy::IntervalOrWrites(interval, writes) => {
+        self.last_sync.elapsed() >= interval || self.unsynced_writes >= writes
+      }
+    };
+    if due {
+      self.write_barrier()?;
+    }
+    Ok(())
+  }
+
+  /// Syncs all writes so far to disk before any later write, e.g. so a data block
+  /// is durable before the index block pointing to it is written.
+  pub fn write_barrier(&mut self) -> io::Result<()> {
+    self.file.sync_data()?;
+    self.metrics.syncs += 1;
+    self.unsynced_writes = 0;
+    self.last_sync = Instant::now();
+    Ok(())
+  }
+
+  /// Persists the meta and syncs all writes so far, and returns a token for this
+  /// point, e.g. to back up only what [`BlockStorage::blocks_since`] it.
+  pub fn checkpoint(&mut self) -> io::Result<Checkpoint> {
+    self.flushMeta()?;
+    self.write_barrier()?;
+    let checkpoint = Checkpoint {
+      sequence: self.checkpoints,
+      offset: self.meta.offset,
+    };
+    self.checkpoints += 1;
+    Ok(checkpoint)
+  }
+
+  /// Moves the file cursor to `position`, skipping the seek if it is already there.
+  fn seekTo(&mut self, position: u64) -> io::Result<()> {
+    if self.cursor != Some(position) {
+      self.cursor = None;
+      self.file.seek(io::SeekFrom::Start(position))?;
+      self.metrics.seeks += 1;
+      self.cursor = Some(position);
+    }
+    Ok(())
+  }
+
+  fn readData(&mut self, position: u64, max_length: u64) -> io::Result<VecDeque<u8>> {
+    if max_length == 0 {
+      return Ok(VecDeque::new());
+    }
+    let file_position = self.base_offset + position;
+    // Never trust `max_length` for the allocation, it may come from a corrupt length.
+    // The file is only asked for its length if the known one is too short.
+    if self.file_len.saturating_sub(file_position) < max_length {
+      self.file_len = self.file.metadata()?.len();
+    }
+    let remaining = self.file_len.saturating_sub(file_position);
+    let buf = self.take_pooled();
+    let buf = match self.io_timeout {
+      None => {
+        self.seekTo(file_position)?;
+        // Unknown until the read succeeds.
+        self.cursor = None;
+        let buf = read_bounded(&mut self.file, remaining, max_length, buf)?;
+        self.cursor = Some(file_position + buf.len() as u64);
+        buf
+      }
+      Some(timeout) => {
+        self.cursor = None;
+        let mut file = PositionedFile::new(&self.file, file_position)?;
+        with_timeout(timeout, move || {
+          read_bounded(&mut file, remaining, max_length, buf)
+        })?
+      }
+    };
+    if (buf.len() as u64) < max_length {
+      // The read ended at the end of the file.
+      self.file_len = file_position + buf.len() as u64;
+    }
+    self.metrics.reads += 1;
+    self.metrics.bytes_read += buf.len() as u64;
+    Ok(buf.into())
+  }
+
+  pub fn claimBlock(&mut self, count: u64) -> io::Result<DataBlock> {
+    self.ensureWritable()?;
+    let end = self
+      .meta
+      .offset
+      .checked_add(count)
+      .and_then(|end| end.checked_mul(self.meta.block_size));
+    if count == 0 || end.is_none() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block count must be positive and fit the file size",
+      ));
+    }
+    if let Some(offset) = self.meta.allocator.allocate(count) {
+      self.flushMeta()?;
+      self.last_block = Some((offset, count));
+      trace!(DEBUG, offset, count, reused = true, "claimed block");
+      return Ok(self.dataBlock(offset, count));
+    }
+    self.meta.offset += count;
+    if self.meta.offset > self.capacity {
+      let capacity = match self.growth {
+        GrowthPolicy::Exact => self.meta.offset,
+        GrowthPolicy::Geometric => self.meta.offset.max(
+          self
+            .capacity
+            .saturating_mul(2)
+            .min(u64::MAX / self.meta.block_size),
+        ),
+      };
+      self.setFileLen(self.base_offset + capacity * self.meta.block_size)?;
+      self.metrics.resizes += 1;
+      self.fillBlocks(self.capacity, capacity)?;
+      self.capacity = capacity;
+    }
+    self.flushMeta()?;
+    let offset = self.meta.offset - count;
+    self.last_block = Some((offset, count));
+    trace!(DEBUG, offset, count, reused = false, "claimed block");
+    Ok(self.dataBlock(offset, count))
+  }
+
+  /// Writes the fill byte over the blocks `[start, end)` the file just grew by.
+  fn fillBlocks(&mut self, start: u64, end: u64) -> io::Result<()> {
+    if self.meta.fill_byte == 0 {
+      return Ok(());
+    }
+    let block_size = self.meta.block_size;
+    let chunk = vec![self.meta.fill_byte; (STREAM_CHUNK_BLOCKS * block_size) as usize];
+    for offset in (start..end).step_by(STREAM_CHUNK_BLOCKS as usize) {
+      let len = (end - offset).min(STREAM_CHUNK_BLOCKS) * block_size;
+      self.writeFlush(offset * block_size, &chunk[..len as usize])?;
+    }
+    Ok(())
+  }
+
+  /// Claims a block for every item and writes it there like [`BlockStorage::writeBlock`],
+  /// with one file write and one meta flush for all of them.
+  ///
+  /// The blocks are appended behind the claimed ones in item order, each as large
+  /// as its item needs; free blocks are not reused. The meta is only written once
+  /// all data is, so a failure leaves none of the items claimed.
+  pub fn bulk_load<I: Iterator<Item = VecDeque<u8>>>(
+    &mut self,
+    items: I,
+  ) -> io::Result<Vec<DataBlock>> {
+    self.ensureWritable()?;
+    let block_size = self.meta.block_size;
+    let alignment = self.meta.payload_alignment;
+    let start = self.meta.offset;
+    let mut buf = Vec::new();
+    let mut blocks = Vec::new();
+    for item in items {
+      let data = match &self.transform {
+        Some(transform) => transform.on_write(Vec::from(item).as_slice()),
+        None => Vec::from(item),
+      };
+      let count = (alignment + data.len() as u64).div_ceil(block_size).max(1);
+      blocks.push(self.dataBlock(start + buf.len() as u64 / block_size, count));
+      let end = buf.len() + (count * block_size) as usize;
+      buf.resize(buf.len() + alignment as usize, self.meta.fill_byte);
+      buf.extend_from_slice(&data);
+      buf.resize(end, self.meta.fill_byte);
+    }
+    if buf.is_empty() {
+      return Ok(blocks);
+    }
+    let end = start + buf.len() as u64 / block_size;
+    if end > self.capacity {
+      self.setFileLen(self.base_offset + end * block_size)?;
+      self.metrics.resizes += 1;
+      self.capacity = end;
+    }
+    self.writeFlush(start * block_size, &buf)?;
+    self.meta.offset = end;
+    self.flushMeta()?;
+    if let Some(block) = blocks.last() {
+      self.last_block = Some((block.offset, block.size / block_size));
+    }
+    Ok(blocks)
+  }
+
+  /// Returns a handle to the block most recently claimed or written, unless it was
+  /// freed since.
+  pub fn last_block(&self) -> Option<DataBlock> {
+    self
+      .last_block
+      .map(|(offset, count)| self.dataBlock(offset, count))
+  }
+
+  /// Returns the offset [`BlockStorage::claimBlock`] would hand out for `count`
+  /// blocks, without claiming them. The count is not validated.
+  pub fn peek_claim(&self, count: u64) -> u64 {
+    self.meta.allocator.peek(count).unwrap_or(self.meta.offset)
+  }
+
+  fn dataBlock(&self, offset: u64, count: u64) -> DataBlock {
+    DataBlock {
+      offset,
+      size: count * self.meta.block_size,
+      payload_offset: self.meta.payload_alignment,
+      block_size: self.meta.block_size,
+    }
+  }
+
+  /// Splits `block` like [`DataBlock::split_at`], failing with `InvalidInput`
+  /// instead of panicking unless both parts hold at least one block.
+  pub fn split_block(
+    &self,
+    block: DataBlock,
+    block_count: u64,
+  ) -> io::Result<(DataBlock, DataBlock)> {
+    let count = block.size / self.meta.block_size;
+    if block_count == 0 || block_count >= count {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Split must leave at least one block on each side",
+      ));
+    }
+    Ok(block.split_at(block_count))
+  }
+
+  /// Returns the blocks of `block` to the free list so later claims can reuse them.
+  ///
+  /// Fails with `InvalidInput` if `block` isn't a claimed block of this storage, see
+  /// [`BlockStorage::is_valid_block`], e.g. when freed twice, as its blocks would
+  /// otherwise be handed out to two owners.
+  pub fn freeBlock(&mut self, block: DataBlock) -> io::Result<()> {
+    self.ensureClaimed(&block)?;
+    let count = block.size / self.meta.block_size;
+    if self
+      .last_block
+      .is_some_and(|(offset, _)| offset == block.offset)
+    {
+      self.last_block = None;
+    }
+    self.meta.allocator.deallocate(block.offset, count);
+    trace!(DEBUG, offset = block.offset, count, "freed block");
+    self.flushMeta()?;
+    self.checkCompaction();
+    Ok(())
+  }
+
+  /// Fails before anything changes if the storage is read-only or `block` isn't
+  /// claimed, e.g. a stale handle to blocks already freed.
+  fn ensureClaimed(&self, block: &DataBlock) -> io::Result<()> {
+    self.ensureWritable()?;
+    if !self.is_valid_block(block) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block is not a claimed block of the storage",
+      ));
+    }
+    Ok(())
+  }
+
+  /// Runs the policy of [`BlockStorage::set_compaction_policy`] once a free is done.
+  fn checkCompaction(&mut self) {
+    if let Some(check) = &mut self.compaction_check {
+      check(&self.meta.allocator);
+    }
+  }
+
+  /// Releases the physical storage backing `block` while keeping the file length.
+  ///
+  /// Intended for blocks that were or are about to be freed; their contents read
+  /// back as zeros afterwards. This uses `fallocate(FALLOC_FL_PUNCH_HOLE)` on Linux
+  /// and is a no-op on other platforms or file systems without hole support.
+  pub fn punch_hole(&mut self, block: &DataBlock) -> io::Result<()> {
+    self.ensureWritable()?;
+    #[cfg(target_os = "linux")]
+    {
+      use std::os::unix::io::AsRawFd;
+      let result = unsafe {
+        libc::fallocate(
+          self.file.as_raw_fd(),
+          libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+          (self.base_offset + block.offset * self.meta.block_size) as libc::off_t,
+          block.size as libc::off_t,
+        )
+      };
+      if result != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+          return Err(err);
+        }
+      }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = block;
+    Ok(())
+  }
+
+  /// Flushes only the storage bytes `[offset, offset + len)` to disk.
+  ///
+  /// Uses `sync_file_range` on Linux, which does not persist file metadata such as
+  /// the length, so it is only suitable for regions inside already synced claims.
+  /// Other platforms fall back to `sync_data`.
+  pub fn sync_range(&mut self, offset: u64, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+      use std::os::unix::io::AsRawFd;
+      let result = unsafe {
+        libc::sync_file_range(
+          self.file.as_raw_fd(),
+          (self.base_offset + offset) as libc::off64_t,
+          len as libc::off64_t,
+          libc::SYNC_FILE_RANGE_WAIT_BEFORE
+            | libc::SYNC_FILE_RANGE_WRITE
+            | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+        )
+      };
+      if result != 0 {
+        return Err(io::Error::last_os_error());
+      }
+      Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+      let _ = (offset, len);
+      self.file.sync_data()
+    }
+  }
+
+  /// Shrinks a multi-block claim to `new_count` blocks, freeing the trailing ones.
+  /// Like [`BlockStorage::freeBlock`] it fails with `InvalidInput` for a block
+  /// that isn't claimed.
+  pub fn truncate_block(&mut self, block: DataBlock, new_count: u64) -> io::Result<DataBlock> {
+    self.ensureClaimed(&block)?;
+    let count = block.size / self.meta.block_size;
+    if new_count == 0 || new_count > count {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Truncated count must be between 1 and the block count",
+      ));
+    }
+    if new_count < count {
+      self
+        .meta
+        .allocator
+        .deallocate(block.offset + new_count, count - new_count);
+      self.flushMeta()?;
+      if self.last_block == Some((block.offset, count)) {
+        self.last_block = Some((block.offset, new_count));
+      }
+      self.checkCompaction();
+    }
+    Ok(DataBlock {
+      size: new_count * self.meta.block_size,
+      ..block
+    })
+  }
+
+  pub fn writeBlockOffset(
+    &mut self,
+    block: &DataBlock,
+    offset: u64,
+    data: VecDeque<u8>,
+  ) -> io::Result<()> {
+    if data.len() as u64 + offset > block.payload_size() {
+      Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Size exceeds block size",
+      ))
+    } else if data.is_empty() {
+      Ok(())
+    } else {
+      // Writing past the end would silently extend a file truncated behind our back.
+      let block_end = block
+        .offset
+        .checked_mul(self.meta.block_size)
+        .and_then(|start| start.checked_add(block.size))
+        .and_then(|end| end.checked_add(self.base_offset));
+      if block_end.is_some_and(|end| end > self.file_len) {
+        self.file_len = self.file.metadata()?.len();
+      }
+      if block_end.is_none_or(|end| end > self.file_len) {
+        return Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          "Block lies beyond the end of the file",
+        ));
+      }
+      let data = Vec::from(data);
+      self.writeFlush(
+        block.offset * self.meta.block_size + block.payload_offset + offset,
+        &data,
+      )?;
+      self.recycle(data);
+      self.last_block = Some((block.offset, block.size / self.meta.block_size));
+      Ok(())
+    }
+  }
+
+  /// Writes `data` to the start of `block`, through the storage's [`Transform`] if any.
+  ///
+  /// The offset variants bypass the transform and access the stored bytes.
+  pub fn writeBlock(&mut self, block: &DataBlock, data: VecDeque<u8>) -> io::Result<()> {
+    let data = match &self.transform {
+      Some(transform) => transform.on_write(Vec::from(data).as_slice()).into(),
+      None => data,
+    };
+    self.writeBlockOffset(block, 0, data)
+  }
+
+  pub fn readBlockOffset(
+    &mut self,
+    block: &DataBlock,
+    offset: u64,
+    max_length: u64,
+  ) -> io::Result<VecDeque<u8>> {
+    self.readData(
+      block.offset * self.meta.block_size + block.payload_offset + offset,
+      max_length,
+    )
+  }
+
+  /// Reads from `offset` up to and including the first `delimiter`, at most
+  /// `max_length` bytes and never past the end of `block`.
+  ///
+  /// Reads a small chunk at a time, so scanning short records doesn't read the
+  /// whole block. Bypasses the transform like the other offset variants.
+  pub fn read_until(
+    &mut self,
+    block: &DataBlock,
+    offset: u64,
+    delimiter: u8,
+    max_length: u64,
+  ) -> io::Result<Vec<u8>> {
+    let limit = max_length.min(block.payload_size().saturating_sub(offset));
+    let mut record = Vec::new();
+    while (record.len() as u64) < limit {
+      let position = offset + record.len() as u64;
+      let length = (limit - record.len() as u64).min(SCAN_CHUNK_SIZE);
+      let chunk = Vec::from(self.readBlockOffset(block, position, length)?);
+      if let Some(index) = chunk.iter().position(|&byte| byte == delimiter) {
+        record.extend_from_slice(&chunk[..=index]);
+        break;
+      }
+      record.extend_from_slice(&chunk);
+      if (chunk.len() as u64) < length {
+        break;
+      }
+    }
+    Ok(record)
+  }
+
+  pub fn readBlock(&mut self, block: &DataBlock) -> io::Result<VecDeque<u8>> {
+    let data = self.readBlockOffset(block, 0, block.payload_size())?;
+    Ok(match &self.transform {
+      Some(transform) => transform.on_read(Vec::from(data).as_slice()).into(),
+      None => data,
+    })
+  }
+
+  /// Reads several blocks like [`BlockStorage::readBlock`] and returns them in the
+  /// order given. Blocks adjacent in the file are read together in a single read.
+  pub fn read_blocks(&mut self, blocks: &[&DataBlock]) -> io::Result<Vec<VecDeque<u8>>> {
+    let block_size = self.meta.block_size;
+    let mut order = (0..blocks.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| blocks[i].offset);
+    let mut reads = vec![VecDeque::new(); blocks.len()];
+    let mut index = 0;
+    while index < order.len() {
+      // Extend the run while the next block starts where the previous one ended.
+      let start = blocks[order[index]].offset;
+      let mut end = index + 1;
+      while end < order.len()
+        && blocks[order[end]].offset * block_size
+          == blocks[order[end - 1]].offset * block_size + blocks[order[end - 1]].size
+      {
+        end += 1;
+      }
+      let run = &order[index..end];
+      let len = run.iter().map(|&i| blocks[i].size).sum();
+      let data = Vec::from(self.readData(start * block_size, len)?);
+      let mut position = 0;
+      for &i in run {
+        let block = blocks[i];
+        // Like `readBlock`, a block cut short by the end of the file reads short.
+        let payload_start = (position + block.payload_offset as usize).min(data.len());
+        let payload_end = (position + block.size as usize).min(data.len());
+        let payload = &data[payload_start..payload_end];
+        reads[i] = match &self.transform {
+          Some(transform) => transform.on_read(payload).into(),
+          None => payload.to_vec().into(),
+        };
+        position += block.size as usize;
+      }
+      index = end;
+    }
+    Ok(reads)
+  }
+
+  /// Copies the payload of `block` to `out` one block-sized chunk at a time and
+  /// returns the number of bytes copied.
+  ///
+  /// With a [`Transform`] the payload is read as a whole, since it can only be
+  /// reversed at once.
+  pub fn copy_block_to<W: Write>(&mut self, block: &DataBlock, out: &mut W) -> io::Result<u64> {
+    if self.transform.is_some() {
+      let data = Vec::from(self.readBlock(block)?);
+      out.write_all(&data)?;
+      return Ok(data.len() as u64);
+    }
+    let start = block.offset * self.meta.block_size + block.payload_offset;
+    let mut copied = 0;
+    while copied < block.payload_size() {
+      let length = (block.payload_size() - copied).min(self.meta.block_size);
+      let chunk = Vec::from(self.readData(start + copied, length)?);
+      out.write_all(&chunk)?;
+      copied += chunk.len() as u64;
+      if (chunk.len() as u64) < length {
+        break;
+      }
+    }
+    Ok(copied)
+  }
+
+  /// Writes `data` behind a self-describing header, so the block can be recognized
+  /// without outside knowledge, e.g. by recovery tools scanning the file.
+  ///
+  /// The header holds a magic, `tag`, the payload length as a varint and a checksum
+  /// of the tag and payload, a CRC-32, and takes up to 18 bytes of the block, 19
+  /// with [`Leb128`](crate::encoding::Leb128) varints.
+  pub fn writeTaggedBlock(&mut self, block: &DataBlock, tag: u8, data: &[u8]) -> io::Result<()> {
+    let mut buf = self.take_buffer();
+    buf.reserve(data.len() + 19);
+    buf.extend(TAGGED_MAGIC);
+    buf.push_back(tag);
+    C::encode(data.len() as u64, &mut buf);
+    // The checksum is filled in once the payload has been checksummed while copied,
+    // a chunk at a time so each is still in cache for the copy.
+    let checksum_at = buf.len();
+    buf.extend([0; 4]);
+    let mut crc = Crc32::new();
+    crc.update(&[tag]);
+    for chunk in data.chunks(SCAN_CHUNK_SIZE as usize) {
+      crc.update(chunk);
+      buf.extend(chunk);
+    }
+    for (byte, checksum) in buf
+      .range_mut(checksum_at..checksum_at + 4)
+      .zip(crc.finalize().to_le_bytes())
+    {
+      *byte = checksum;
+    }
+    self.writeBlock(block, buf)
+  }
+
+  /// Reads a block written by [`BlockStorage::writeTaggedBlock`] and returns its
+  /// tag and payload, failing with `InvalidData` if the header or checksum is off.
+  ///
+  /// If a [`BlockStorage::set_checksum_failure_handler`] is set, a block failing
+  /// verification is replaced with the bytes the handler returns, if any.
+  pub fn readTaggedBlock(&mut self, block: &DataBlock) -> io::Result<(u8, VecDeque<u8>)> {
+    let data = self.readBlock(block)?;
+    let err = match self.verify_tagged(block, data) {
+      Err(err) => err,
+      result => return result,
+    };
+    trace!(WARN, offset = block.offset, error = %err, "tagged block failed verification");
+    let Some(repaired) = self
+      .checksum_failure_handler
+      .as_ref()
+      .and_then(|f| f(block))
+    else {
+      return Err(err);
+    };
+    let parsed = parse_tagged::<C>(VecDeque::from(repaired.clone()))
+      .map_err(|kind| io::Error::new(io::ErrorKind::InvalidData, kind.to_string()))?;
+    self.writeBlock(block, repaired.into())?;
+    Ok(parsed)
+  }
+
+  /// Reads a block written by [`BlockStorage::writeTaggedBlock`] like
+  /// [`BlockStorage::readTaggedBlock`], but stricter, for untrusted files.
+  ///
+  /// `block` must be a claimed block of this storage, and a block failing
+  /// verification is reported as [`LeaflessError::Corrupted`] instead of repaired.
+  pub fn readBlockChecked(&mut self, block: &DataBlock) -> io::Result<(u8, VecDeque<u8>)> {
+    if !self.is_valid_block(block) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block is not a claimed block",
+      ));
+    }
+    let data = self.readBlock(block)?;
+    let (tag, data) = self.verify_tagged(block, data).map_err(|err| {
+      io::Error::from(LeaflessError::Corrupted {
+        offset: block.offset,
+        reason: err.to_string(),
+      })
+    })?;
+    debug_assert!(data.len() as u64 <= block.payload_size());
+    Ok((tag, data))
+  }
+
+  /// Parses a tagged block read from `block`, reporting a failed verification to
+  /// the corruption handler.
+  fn verify_tagged(&self, block: &DataBlock, data: VecDeque<u8>) -> io::Result<(u8, VecDeque<u8>)> {
+    parse_tagged::<C>(data).map_err(|kind| {
+      if let Some(handler) = &self.corruption_handler {
+        handler(&CorruptionEvent {
+          offset: block.offset,
+          kind,
+        });
+      }
+      io::Error::new(io::ErrorKind::InvalidData, kind.to_string())
+    })
+  }
+
+  /// Sets the handler notified of every tagged block that fails verification, e.g.
+  /// for central alerting. The failing read still returns its error.
+  pub fn set_corruption_handler(&mut self, handler: Option<CorruptionHandler>) {
+    self.corruption_handler = handler;
+  }
+
+  /// Sets the handler asked for the correct contents of a tagged block that failed
+  /// verification, e.g. from a replica. It returns the block as written by
+  /// [`BlockStorage::writeTaggedBlock`], which is then rewritten, or `None` to fail
+  /// the read.
+  pub fn set_checksum_failure_handler(&mut self, handler: Option<ChecksumFailureHandler>) {
+    self.checksum_failure_handler = handler;
+  }
+
+  /// Decodes the length-prefixed run of u64s written to the start of `block`,
+  /// e.g. with [`Encoder::encode_u64_slice_with`] and the storage's codec.
+  pub fn read_values(&mut self, block: &DataBlock) -> Result<Vec<u64>, DecodeError> {
+    Decoder::decode_u64_slice_with::<C>(&mut self.readBlock(block)?)
+  }
+
+  /// Writes `record` to the start of `block` behind its encoded length, through
+  /// the storage's [`Transform`] if any.
+  pub fn write_record<T: Encode + ?Sized>(
+    &mut self,
+    block: &DataBlock,
+    record: &T,
+  ) -> io::Result<()> {
+    let len = record.encoded_size();
+    let mut buf = Encoder::encode_u64_with::<C>(len as u64);
+    buf.reserve(len);
+    record.encode(&mut buf);
+    self.writeBlock(block, buf)
+  }
+
+  /// Decodes a record written by [`BlockStorage::write_record`]. Decoding is
+  /// limited to the record's length, so the rest of the block is never read as
+  /// part of it.
+  pub fn read_record<T: Decode>(&mut self, block: &DataBlock) -> Result<T, DecodeError> {
+    let mut data = self.readBlock(block)?;
+    let len = C::decode(&mut data)?;
+    if len > data.len() as u64 {
+      return Err(DecodeError::UnexpectedEof);
+    }
+    T::decode(&mut data.drain(..len as usize).collect())
+  }
+
+  /// Reads the `count` blocks starting at block `start_offset` with a single read.
+  pub fn read_block_range(&mut self, start_offset: u64, count: u64) -> io::Result<Vec<u8>> {
+    let in_range = start_offset >= self.meta.meta_blocks
+      && start_offset
+        .checked_add(count)
+        .is_some_and(|end| end <= self.meta.offset);
+    if !in_range {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block range exceeds claimed blocks",
+      ));
+    }
+    Ok(
+      self
+        .readData(
+          start_offset * self.meta.block_size,
+          count * self.meta.block_size,
+        )?
+        .into(),
+    )
+  }
+
+  /// Reads the whole claimed region into memory and returns a read-only view of it.
+  /// The view reads through the storage's [`Transform`], if any.
+  pub fn load_into_memory(&mut self) -> io::Result<MemBlockStorage> {
+    let data = self.readData(0, self.meta.offset * self.meta.block_size)?;
+    Ok(MemBlockStorage {
+      data: data.into(),
+      block_size: self.meta.block_size,
+      transform: self.transform.clone(),
+    })
+  }
+
+  /// Copies the storage file to `path`, replacing any file there atomically, so a
+  /// crash leaves either the old backup or the new one, see [`atomic::atomic_write`].
+  ///
+  /// The copy opens like the storage itself. The meta file of a raw storage isn't
+  /// part of it.
+  pub fn backup_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+    self.file.seek(io::SeekFrom::Start(0))?;
+    self.cursor = None;
+    let source = &self.file;
+    atomic::atomic_write(path.as_ref(), |temp| {
+      io::copy(&mut &*source, temp).map(|_| ())
+    })
+  }
+
+  /// Hashes the payload of `block`, so blocks holding identical data hash equal
+  /// whatever follows it in the block.
+  ///
+  /// A block written by [`BlockStorage::writeTaggedBlock`] records its length, so
+  /// only its tag and payload are hashed. Other blocks don't, so trailing fill
+  /// bytes are taken as padding: data ending in the fill byte hashes like the
+  /// same data without it.
+  pub fn block_hash(&mut self, block: &DataBlock) -> io::Result<u64> {
+    let data = self.readBlock(block)?;
+    let mut hasher = Fnv1a64::new();
+    let tagged = tagged_len::<C>(&data).and_then(|_| parse_tagged::<C>(data.clone()).ok());
+    let payload = match tagged {
+      Some((tag, payload)) => {
+        hasher.update(&[tag]);
+        payload
+      }
+      None => {
+        let fill = self.meta.fill_byte;
+        let len = data
+          .iter()
+          .rposition(|&byte| byte != fill)
+          .map_or(0, |i| i + 1);
+        data.range(..len).copied().collect()
+      }
+    };
+    let (head, tail) = payload.as_slices();
+    hasher.update(head);
+    hasher.update(tail);
+    Ok(hasher.finish())
+  }
+
+  /// Returns the block `block_ref` refers to, failing with `InvalidInput` if it is
+  /// no claimed block of this storage, see [`BlockStorage::is_valid_block`].
+  pub fn block_from_ref(&self, block_ref: BlockRef) -> io::Result<DataBlock> {
+    let block = DataBlock {
+      offset: block_ref.offset,
+      size: block_ref.size,
+      payload_offset: self.meta.payload_alignment,
+      block_size: self.meta.block_size,
+    };
+    if !block_ref.size.is_multiple_of(self.meta.block_size) || !self.is_valid_block(&block) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Reference is not a claimed block",
+      ));
+    }
+    Ok(block)
+  }
+
+  /// Checks that `block` lies entirely within the claimed region of this
+  /// storage and none of it is free, so stale or foreign handles can be
+  /// rejected before reading.
+  pub fn is_valid_block(&self, block: &DataBlock) -> bool {
+    let count = block.size / self.meta.block_size;
+    block.offset >= self.meta.meta_blocks
+      && count > 0
+      && block
+        .offset
+        .checked_add(count)
+        .is_some_and(|end| end <= self.meta.offset)
+      && !self.meta.allocator.overlaps_free(block.offset, count)
+  }
+}
+
+/// A read-only in-memory copy of a storage, see [`BlockStorage::load_into_memory`].
+///
+/// Reads behave like those of the storage it was loaded from, without any syscall.
+/// Writes are rejected.
+pub struct MemBlockStorage {
+  data: Vec<u8>,
+  block_size: u64,
+  transform: Option<Rc<dyn Transform>>,
+}
+
+#[allow(non_snake_case)]
+impl MemBlockStorage {
+  pub fn writeBlock(&mut self, _block: &DataBlock, _data: VecDeque<u8>) -> io::Result<()> {
+    Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      "In-memory storage is read-only",
+    ))
+  }
+
+  pub fn readBlockOffset(
+    &self,
+    block: &DataBlock,
+    offset: u64,
+    max_length: u64,
+  ) -> io::Result<VecDeque<u8>> {
+    let len = self.data.len() as u64;
+    let start = (block.offset * self.block_size + block.payload_offset)
+      .saturating_add(offset)
+      .min(len);
+    let end = start.saturating_add(max_length).min(len);
+    Ok(
+      self.data[start as usize..end as usize]
+        .iter()
+        .copied()
+        .collect(),
+    )
+  }
+
+  /// Reads `block` like [`BlockStorage::readBlock`], through the storage's
+  /// [`Transform`] if any. The offset variant bypasses it.
+  pub fn readBlock(&self, block: &DataBlock) -> io::Result<VecDeque<u8>> {
+    let data = self.readBlockOffset(block, 0, block.payload_size())?;
+    Ok(match &self.transform {
+      Some(transform) => transform.on_read(Vec::from(data).as_slice()).into(),
+      None => data,
+    })
+  }
+}
+
+fn read_stream_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+  Decoder::read_u64(input).map_err(|err| match err {
+    DecodeError::Io(err) => err,
+    err => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+  })
+}
+
+/// Reads exactly `len` bytes, allocating only as much as the input holds.
+fn read_stream_bytes<R: Read>(input: &mut R, len: u64) -> io::Result<Vec<u8>> {
+  let mut data = Vec::new();
+  input.take(len).read_to_end(&mut data)?;
+  if (data.len() as u64) < len {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "Stream ended inside a record",
+    ));
+  }
+  Ok(data)
+}
+
+/// See [`BlockStorage::fragmentation`].
+fn fragmentation(list: &FreeList) -> f64 {
+  let free_blocks = list.free_blocks();
+  if free_blocks == 0 {
+    return 0.0;
+  }
+  list.extent_count() as f64 / free_blocks as f64
+}
+
+/// Length of the tagged block starting `data`, header included, if `data` starts
+/// with a tagged header. The checksum is left to [`parse_tagged`].
+fn tagged_len<C: VarintCodec>(data: &VecDeque<u8>) -> Option<u64> {
+  let prefix = TAGGED_MAGIC.len() + 1;
+  let header = data.iter().copied().take(prefix + 10).collect::<Vec<_>>();
+  if header.len() <= prefix || header[..TAGGED_MAGIC.len()] != TAGGED_MAGIC {
+    return None;
+  }
+  let mut rest = &header[prefix..];
+  let varint_len = rest.len();
+  let len = C::read(&mut rest).ok()?;
+  let header_len = prefix + varint_len - rest.len() + 4;
+  len.checked_add(header_len as u64)
+}
+
+/// Splits a block written by [`BlockStorage::writeTaggedBlock`] into tag and payload.
+fn parse_tagged<C: VarintCodec>(
+  mut data: VecDeque<u8>,
+) -> Result<(u8, VecDeque<u8>), CorruptionKind> {
+  if data.len() < TAGGED_MAGIC.len() + 1 || data.drain(..TAGGED_MAGIC.len()).ne(TAGGED_MAGIC) {
+    return Err(CorruptionKind::Magic);
+  }
+  let tag = data.pop_front().unwrap_or_default();
+  let len = C::decode(&mut data).map_err(|_| CorruptionKind::Length)?;
+  let checksum = Decoder::decode_fixed::<4>(&mut data).map_err(|_| CorruptionKind::Length)?;
+  if len > data.len() as u64 {
+    return Err(CorruptionKind::Length);
+  }
+  data.truncate(len as usize);
+  if tagged_checksum(tag, &data) != u32::from_le_bytes(checksum) {
+    return Err(CorruptionKind::Checksum);
+  }
+  Ok((tag, data))
+}
+
+fn tagged_checksum(tag: u8, data: &VecDeque<u8>) -> u32 {
+  let (head, tail) = data.as_slices();
+  let mut crc = Crc32::new();
+  crc.update(&[tag]);
+  crc.update(head);
+  crc.update(tail);
+  crc.finalize()
+}
+
+/// Reads up to `max_length` bytes from wherever `file` is positioned, fewer only
+/// if it ends, allocating for at most the `remaining` bytes the file was last
+/// known to hold. Reads into `buf`, which is cleared first so it can be a reused
+/// buffer.
+fn read_bounded<R: Read>(
+  file: &mut R,
+  remaining: u64,
+  max_length: u64,
+  mut buf: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+  buf.clear();
+  buf.resize(max_length.min(remaining) as usize, 0);
+  let mut total_read: u64 = 0;
+  loop {
+    if total_read as usize == buf.len() {
+      if total_read == max_length {
+        break;
+      }
+      // The file grew since its length was queried.
+      let grow = (max_length - total_read).min(BLOCK_SIZE);
+      buf.resize(buf.len() + grow as usize, 0);
+    }
+    let slice = &mut buf[(total_read as usize)..];
+    let read = file.read(slice)?;
+    total_read += read as u64;
+    if read == 0 {
+      break;
+    }
+  }
+  buf.truncate(total_read as usize);
+  Ok(buf)
+}
+
+/// A clone of the storage file read and written at its own position with positional
+/// I/O, for operations run by [`with_timeout`]. An abandoned operation that still
+/// completes then lands where it was meant to, instead of wherever the shared file
+/// cursor has been moved since, and leaves that cursor alone.
+struct PositionedFile {
+  file: fs::File,
+  position: u64,
+}
+
+impl PositionedFile {
+  fn new(file: &fs::File, position: u64) -> io::Result<PositionedFile> {
+    Ok(PositionedFile {
+      file: file.try_clone()?,
+      position,
+    })
+  }
+}
+
+impl Read for PositionedFile {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    #[cfg(unix)]
+    let read = std::os::unix::fs::FileExt::read_at(&self.file, buf, self.position)?;
+    #[cfg(windows)]
+    let read = std::os::windows::fs::FileExt::seek_read(&self.file, buf, self.position)?;
+    self.position += read as u64;
+    Ok(read)
+  }
+}
+
+impl Write for PositionedFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    #[cfg(unix)]
+    let written = std::os::unix::fs::FileExt::write_at(&self.file, buf, self.position)?;
+    #[cfg(windows)]
+    let written = std::os::windows::fs::FileExt::seek_write(&self.file, buf, self.position)?;
+    self.position += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+/// Runs `op` on its own thread and fails with `TimedOut` if it takes longer than `timeout`.
+fn with_timeout<T: Send + 'static>(
+  timeout: Duration,
+  op: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    // The receiver is gone if the operation timed out.
+    let _ = sender.send(op());
+  });
+  match receiver.recv_timeout(timeout) {
+    Ok(result) => result,
+    Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+      io::ErrorKind::TimedOut,
+      "I/O operation timed out",
+    )),
+    Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::other("I/O operation panicked")),
+  }
+}
+
+fn retry_transient<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+  let mut retries = 0;
+  loop {
+    match op() {
+      Err(err)
+        if retries < WRITE_RETRIES
+          && matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WriteZero
+          ) =>
+      {
+        retries += 1
+      }
+      result => return result,
+    }
+  }
+}
+
+/// Writes and flushes `data` at the current position, retrying transient failures a
+/// bounded number of times. Hard failures are reported as [`LeaflessError::Write`].
+fn write_flush_retrying<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+  let len = data.len() as u64;
+  let mut written = 0;
+  while written < data.len() {
+    let result = retry_transient(|| match out.write(&data[written..]) {
+      Ok(0) => Err(io::Error::new(
+        io::ErrorKind::WriteZero,
+        "Failed to write whole buffer",
+      )),
+      result => result,
+    });
+    match result {
+      Ok(count) => written += count,
+      Err(source) => {
+        let written = written as u64;
+        return Err(
+          LeaflessError::Write {
+            written,
+            len,
+            source,
+          }
+          .into(),
+        );
+      }
+    }
+  }
+  retry_transient(|| out.flush()).map_err(|source| {
+    LeaflessError::Write {
+      written: len,
+      len,
+      source,
+    }
+    .into()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::BLOCK_SIZE;
+  use super::{
+    with_timeout, write_flush_retrying, BlockDiff, BlockRef, BlockStorage, BlockStorageMeta,
+    CompactionPolicy, CorruptionEvent, CorruptionKind, DataBlock, GrowthPolicy, StorageOptions,
+    SyncPolicy, HEADER_SIZE, TAGGED_MAGIC,
+  };
+  use crate::{
+    allocator::Allocator,
+    encoding::{
+      Decode, DecodeError, Decoder, Encode, Encoder, LeaflessVarint, Leb128, VarintCodec,
+    },
+    error::LeaflessError,
+    free_list::FreeList,
+    metrics::Metrics,
+    transform::Transform,
+  };
+  use rand::{self, Rng};
+  use std::cell::RefCell;
+  use std::collections::VecDeque;
+  use std::env::temp_dir;
+  use std::fs;
+  use std::io::{self, Seek, Write};
+  use std::rc::Rc;
+  use std::time::Duration;
+
+  fn create_temp_file_name() -> std::path::PathBuf {
+    let temp_file_name: String = rand::thread_rng()
+      .sample_iter(&rand::distributions::Alphanumeric)
+      .take(16)
+      .map(char::from)
+      .collect();
+
+    temp_dir().join(temp_file_name + ".leafless")
+  }
+
+  fn create_temp_storage() -> BlockStorage {
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    BlockStorage::create(open.open(create_temp_file_name()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_create_block_storage() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
+    let storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.offset, 1);
+  }
+
+  #[test]
+  fn test_claim_storage() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let data = "data".chars().map(|c| c as u8).collect::<VecDeque<_>>();
+    storage.writeBlock(&block, data.clone()).unwrap();
+    let mut read = storage.readBlock(&block).unwrap();
+    read.resize(4, 0);
+    assert_eq!(read, data);
+    read.resize(BLOCK_SIZE as usize + 1, 0);
+    assert!(storage.writeBlock(&block, read).is_err());
+  }
+
+  #[test]
+  fn test_block_hash() {
+    let mut storage = create_temp_storage();
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(1).unwrap();
+    let third = storage.claimBlock(1).unwrap();
+    let data = VecDeque::from(b"same payload".to_vec());
+    storage.writeBlock(&first, data.clone()).unwrap();
+    storage.writeBlock(&second, data).unwrap();
+    storage
+      .writeBlock(&third, VecDeque::from(b"other payload".to_vec()))
+      .unwrap();
+    let hash = storage.block_hash(&first).unwrap();
+    assert_eq!(hash, storage.block_hash(&second).unwrap());
+    assert_ne!(hash, storage.block_hash(&third).unwrap());
+
+    // Padding behind the payload doesn't count, written or not.
+    storage
+      .writeBlockOffset(&second, 100, VecDeque::from(vec![0; 50]))
+      .unwrap();
+    assert_eq!(hash, storage.block_hash(&second).unwrap());
+
+    // A tagged payload is hashed by its recorded length, so leftovers of earlier
+    // writes behind it don't count either.
+    storage
+      .writeBlock(&third, VecDeque::from(vec![b'x'; 200]))
+      .unwrap();
+    storage
+      .writeTaggedBlock(&third, 1, b"same payload")
+      .unwrap();
+    storage
+      .writeTaggedBlock(&first, 1, b"same payload")
+      .unwrap();
+    let tagged = storage.block_hash(&first).unwrap();
+    assert_eq!(tagged, storage.block_hash(&third).unwrap());
+    storage
+      .writeTaggedBlock(&first, 2, b"same payload")
+      .unwrap();
+    assert_ne!(tagged, storage.block_hash(&first).unwrap());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn test_punch_hole() {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(64).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(vec![1u8; block.size() as usize]))
+      .unwrap();
+    storage.file.sync_all().unwrap();
+    let before = storage.file.metadata().unwrap();
+    storage.punch_hole(&block).unwrap();
+    storage.freeBlock(block).unwrap();
+    let after = storage.file.metadata().unwrap();
+    assert_eq!(after.len(), before.len());
+    assert!(after.blocks() < before.blocks());
+  }
+
+  /// Accepts at most `chunk` bytes per write and fails the writes listed in `failures`.
+  struct FlakyWriter {
+    data: Vec<u8>,
+    chunk: usize,
+    writes: usize,
+    failures: Vec<(usize, io::ErrorKind)>,
+  }
+
+  impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.writes += 1;
+      if let Some(&(_, kind)) = self.failures.iter().find(|(at, _)| *at == self.writes) {
+        return Err(io::Error::new(kind, "injected failure"));
+      }
+      let count = buf.len().min(self.chunk);
+      self.data.extend_from_slice(&buf[..count]);
+      Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_write_retries_transient_failure() {
+    let mut writer = FlakyWriter {
+      data: Vec::new(),
+      chunk: usize::MAX,
+      writes: 0,
+      failures: vec![(1, io::ErrorKind::WriteZero)],
+    };
+    write_flush_retrying(&mut writer, b"payload").unwrap();
+    assert_eq!(writer.data, b"payload");
+    assert_eq!(writer.writes, 2);
+  }
+
+  #[test]
+  fn test_write_reports_partial_failure() {
+    let mut writer = FlakyWriter {
+      data: Vec::new(),
+      chunk: 4,
+      writes: 0,
+      failures: vec![(2, io::ErrorKind::Other)],
+    };
+    let err = write_flush_retrying(&mut writer, b"payload").unwrap_err();
+    let err = LeaflessError::from_io(&err).unwrap();
+    assert!(err.is_partial());
+    assert!(matches!(err, LeaflessError::Write { written: 4, .. }));
+  }
+
+  /// Never reuses freed blocks, so every claim extends the file.
+  struct BumpAllocator {
+    deallocated: u64,
+  }
+
+  impl Allocator for BumpAllocator {
+    fn allocate(&mut self, _: u64) -> Option<u64> {
+      None
+    }
+
+    fn peek(&self, _: u64) -> Option<u64> {
+      None
+    }
+
+    fn deallocate(&mut self, _: u64, count: u64) {
+      self.deallocated += count;
+    }
+
+    fn overlaps_free(&self, _: u64, _: u64) -> bool {
+      false
+    }
+
+    fn serialize(&self) -> VecDeque<u8> {
+      Encoder::encode_u64(self.deallocated)
+    }
+
+    fn deserialize(&mut self, data: &mut VecDeque<u8>) {
+      self.deallocated = Decoder::decode_u64(data);
+    }
+  }
+
+  #[test]
+  fn test_custom_allocator() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::<BumpAllocator>::create_with_allocator(
+      open.open(file_name.clone()).unwrap(),
+      StorageOptions::default(),
+      BumpAllocator { deallocated: 0 },
+    )
+    .unwrap();
+    let block = storage.claimBlock(2).unwrap();
+    storage.freeBlock(block).unwrap();
+    assert_eq!(storage.claimBlock(2).unwrap().offset, 3);
+
+    drop(storage);
+    let storage = BlockStorage::<BumpAllocator>::open_with_allocator(
+      open.open(file_name).unwrap(),
+      BumpAllocator { deallocated: 0 },
+    )
+    .unwrap();
+    assert_eq!(storage.meta.allocator.deallocated, 2);
+    assert_eq!(storage.meta.offset, 5);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn test_sync_range() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"durable".to_vec()))
+      .unwrap();
+    storage
+      .sync_range(block.offset * BLOCK_SIZE, block.size())
+      .unwrap();
+    let mut read = storage.readBlock(&block).unwrap();
+    read.truncate(7);
+    assert_eq!(read, b"durable".to_vec());
+  }
+
+  #[test]
+  fn test_claim_count_validation() {
+    let mut storage = create_temp_storage();
+    assert!(storage.claimBlock(u64::MAX).is_err());
+    assert!(storage.claimBlock(u64::MAX / BLOCK_SIZE).is_err());
+    assert!(storage.claimBlock(0).is_err());
+    assert_eq!(storage.meta.offset, 1);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+  }
+
+  #[test]
+  fn test_double_free() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(2).unwrap();
+    let stale = storage.dataBlock(block.offset, 2);
+    storage.freeBlock(block).unwrap();
+    let err = storage.freeBlock(stale).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    // Part of a free extent, past the claimed region and the meta block.
+    let err = storage.freeBlock(storage.dataBlock(2, 1)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(storage.freeBlock(storage.dataBlock(5, 1)).is_err());
+    assert!(storage.freeBlock(storage.dataBlock(0, 1)).is_err());
+    assert_eq!(
+      storage.meta.allocator.extents().collect::<Vec<_>>(),
+      vec![(1, 2)]
+    );
+    assert_eq!(storage.claimBlock(2).unwrap().offset, 1);
+    assert_eq!(storage.claimBlock(2).unwrap().offset, 3);
+  }
+
+  #[test]
+  fn test_zero_length_io() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let position = storage.file.stream_position().unwrap();
+    storage.writeBlock(&block, VecDeque::new()).unwrap();
+    assert_eq!(storage.file.stream_position().unwrap(), position);
+    let read = storage.readBlockOffset(&block, 0, 0).unwrap();
+    assert!(read.is_empty());
+    assert_eq!(storage.file.stream_position().unwrap(), position);
+  }
+
+  #[test]
+  fn test_meta_golden_bytes() {
+    let mut meta = BlockStorageMeta::new(1, FreeList::new());
+    meta.offset = 200;
+    meta.allocator.deallocate(3, 2);
+    // offset, meta_blocks, payload_alignment, transform, block_size, fill_byte,
+    // format version, codec, body length and checksum, then the body: free extent count, (offset, count)
+    // per extent, the app metadata length and bytes.
+    assert_eq!(
+      Vec::from(meta.serialize()),
+      vec![
+        0xC8, 0x01, 0x01, 0x00, 0x00, 0x80, 0x20, 0x00, 0x01, 0x00, 0x04, 0xB7, 0x19, 0x56, 0x56,
+        0x77, 0x07, 0x2E, 0xC7, 0x01, 0x03, 0x02, 0x00
+      ]
+    );
+  }
+
+  #[test]
+  fn test_meta_flush_is_incremental() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
+    let blocks = (0..800)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    for block in blocks.into_iter().step_by(2) {
+      storage.freeBlock(block).unwrap();
+    }
+    let full = storage.meta.serialize().len();
+    for _ in 0..1000 {
+      // Single-block holes can't hold two blocks, so these only bump the offset.
+      storage.claimBlock(2).unwrap();
+      assert!(!storage.meta.allocator.is_dirty());
+    }
+    let written = storage.writeMeta().unwrap();
+    assert!(written <= HEADER_SIZE as usize);
+    assert!(written * 100 < full);
+
+    let offset = storage.meta.offset;
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.offset, offset);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+  }
+
+  #[test]
+  fn test_read_absurd_length() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let read = storage.readBlockOffset(&block, 0, u64::MAX / 2).unwrap();
+    assert_eq!(read.len() as u64, BLOCK_SIZE);
+  }
+
+  #[test]
+  fn test_sync_meta() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
+    storage.meta.offset = 7;
+    storage.sync_meta().unwrap();
+    drop(storage);
+    let storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.offset, 7);
+  }
+
+  #[test]
+  fn test_read_header() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
+    storage.claimBlock(3).unwrap();
+    let header = BlockStorage::read_header(&file_name).unwrap();
+    assert_eq!(header.block_size(), BLOCK_SIZE);
+    assert_eq!(header.block_count(), 4);
+    assert_eq!(header.meta_blocks(), 1);
+    assert_eq!(header.codec(), LeaflessVarint::ID);
+  }
+
+  #[test]
+  fn test_varint_codec() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::<FreeList, Leb128>::create_with_allocator(
+      open.open(file_name.clone()).unwrap(),
+      StorageOptions::default(),
+      FreeList::new(),
+    )
+    .unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    storage.writeTaggedBlock(&block, 7, b"tagged").unwrap();
+    let values = storage.claimBlock(1).unwrap();
+    let large = [u64::MAX, 1];
+    storage
+      .writeBlock(&values, Encoder::encode_u64_slice_with::<Leb128>(&large))
+      .unwrap();
+    assert_eq!(
+      BlockStorage::read_header(&file_name).unwrap().codec(),
+      Leb128::ID
+    );
+
+    drop(storage);
+    let err = BlockStorage::open(open.open(file_name.clone()).unwrap())
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut storage = BlockStorage::<FreeList, Leb128>::open_with_allocator(
+      open.open(file_name.clone()).unwrap(),
+      FreeList::new(),
+    )
+    .unwrap();
+    assert_eq!(
+      storage.readTaggedBlock(&block).unwrap(),
+      (7, b"tagged".to_vec().into())
+    );
+    assert_eq!(storage.read_values(&values).unwrap(), large);
+
+    // A file of another format version is refused as well.
+    storage.meta.version = 2;
+    storage.flushMeta().unwrap();
+    drop(storage);
+    let err = BlockStorage::<FreeList, Leb128>::open_with_allocator(
+      open.open(file_name).unwrap(),
+      FreeList::new(),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn test_invalid_layout() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let layouts = [
+      (BLOCK_SIZE, BLOCK_SIZE),
+      (BLOCK_SIZE, 48),
+      (1000, 0),
+      (256, 0),
+    ];
+    for (block_size, payload_alignment) in layouts {
+      let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+      storage.meta.block_size = block_size;
+      storage.meta.payload_alignment = payload_alignment;
+      storage.flushMeta().unwrap();
+      drop(storage);
+      for err in [
+        BlockStorage::open(open.open(&file_name).unwrap()).err(),
+        BlockStorage::open_read_only(open.open(&file_name).unwrap()).err(),
+      ] {
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidData);
+      }
+    }
+  }
+
+  #[test]
+  fn test_meta_reservation() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let storage_options = StorageOptions {
+      meta_blocks: 4,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(file_name.clone()).unwrap(), storage_options)
+        .unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    assert_eq!(block.offset, 4);
+    assert!(storage.is_valid_block(&block));
+    let meta = DataBlock {
+      offset: 3,
+      size: BLOCK_SIZE,
+      payload_offset: 0,
+      block_size: BLOCK_SIZE,
+    };
+    assert!(!storage.is_valid_block(&meta));
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.meta_blocks, 4);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 5);
+  }
+
+  #[test]
+  fn test_is_valid_block() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(2).unwrap();
+    assert!(storage.is_valid_block(&block));
+
+    let mut other = create_temp_storage();
+    other.claimBlock(2).unwrap();
+    let stale = other.claimBlock(1).unwrap();
+    assert!(!storage.is_valid_block(&stale));
+    assert!(other.is_valid_block(&stale));
+
+    let meta = DataBlock {
+      offset: 0,
+      size: BLOCK_SIZE,
+      payload_offset: 0,
+      block_size: BLOCK_SIZE,
+    };
+    assert!(!storage.is_valid_block(&meta));
+
+    let offset = block.offset;
+    storage.freeBlock(block).unwrap();
+    let freed = DataBlock {
+      offset,
+      size: 2 * BLOCK_SIZE,
+      payload_offset: 0,
+      block_size: BLOCK_SIZE,
+    };
+    assert!(!storage.is_valid_block(&freed));
+  }
+
+  #[test]
+  fn test_truncate_block() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(4).unwrap();
+    let offset = block.offset;
+    let block = storage.truncate_block(block, 2).unwrap();
+    assert_eq!(block.size(), 2 * BLOCK_SIZE);
+    assert!(storage.is_valid_block(&block));
+
+    let reused = storage.claimBlock(2).unwrap();
+    assert_eq!(reused.offset, offset + 2);
+    assert_eq!(storage.meta.offset, 5);
+    assert!(storage.truncate_block(block, 3).is_err());
+
+    // A second handle to the truncated blocks must not free their tail again.
+    let block = storage.claimBlock(4).unwrap();
+    let block_ref = BlockRef::from(&block);
+    let stale = storage.block_from_ref(block_ref).unwrap();
+    storage.truncate_block(block, 2).unwrap();
+    let free_blocks = storage.meta.allocator.free_blocks();
+    let err = storage.truncate_block(stale, 2).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(storage.meta.allocator.free_blocks(), free_blocks);
+  }
+
+  #[test]
+  fn test_repair() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let layout = || StorageOptions {
+      block_size: 512,
+      payload_alignment: 64,
+      fill_byte: 0xFF,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(&file_name).unwrap(), layout()).unwrap();
+    let freed = storage.claimBlock(1).unwrap();
+    let tagged = storage.claimBlock(3).unwrap();
+    let plain = storage.claimBlock(1).unwrap();
+    let trailing = storage.claimBlock(2).unwrap();
+    // The fill byte, so only the tagged header tells the later blocks are in use.
+    let payload = vec![0xFF; 1400];
+    storage.writeTaggedBlock(&tagged, 3, &payload).unwrap();
+    storage
+      .writeBlock(&plain, VecDeque::from(b"survivor".to_vec()))
+      .unwrap();
+    storage.freeBlock(freed).unwrap();
+    storage.freeBlock(trailing).unwrap();
+    storage.writeFlush(0, &[0u8; 512]).unwrap();
+
+    drop(storage);
+    assert!(BlockStorage::open(open.open(&file_name).unwrap()).is_err());
+    let mut storage = BlockStorage::repair(open.open(&file_name).unwrap(), layout()).unwrap();
+    assert_eq!(storage.meta.offset, 6);
+    assert_eq!(
+      storage.meta.allocator.extents().collect::<Vec<_>>(),
+      vec![(1, 1)]
+    );
+    assert_eq!(
+      storage.readTaggedBlock(&tagged).unwrap(),
+      (3, payload.into())
+    );
+    let read = storage.readBlockOffset(&plain, 0, 8).unwrap();
+    assert_eq!(read, b"survivor".to_vec());
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.block_size, 512);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 6);
+    assert_eq!(storage.metrics().resizes, 0);
+  }
+
+  #[test]
+  fn test_metrics() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    storage.readBlock(&block).unwrap();
+    storage.readBlockOffset(&block, 0, 0).unwrap();
+    // The create writes the whole meta, 20 bytes here, the claim only its 9 byte
+    // header, and then the 4 data bytes.
+    assert_eq!(
+      storage.metrics(),
+      Metrics {
+        reads: 1,
+        writes: 3,
+        flushes: 3,
+        resizes: 1,
+        syncs: 0,
+        seeks: 4,
+        bytes_read: BLOCK_SIZE,
+        bytes_written: 20 + 9 + 4,
+      }
+    );
+  }
+
+  #[test]
+  fn test_read_block_range() {
+    let mut storage = create_temp_storage();
+    let blocks = (0..3)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+      storage
+        .writeBlock(block, VecDeque::from(vec![i as u8 + 1; 8]))
+        .unwrap();
+    }
+    let reads = storage.metrics().reads;
+    let range = storage.read_block_range(blocks[0].offset, 3).unwrap();
+    assert_eq!(storage.metrics().reads, reads + 1);
+    assert_eq!(range.len() as u64, 3 * BLOCK_SIZE);
+    for (i, block) in blocks.iter().enumerate() {
+      let start = i * BLOCK_SIZE as usize;
+      assert_eq!(
+        range[start..start + BLOCK_SIZE as usize],
+        Vec::from(storage.readBlock(block).unwrap())
+      );
+    }
+    assert!(storage.read_block_range(blocks[1].offset, 3).is_err());
+    assert!(storage.read_block_range(0, 1).is_err());
+  }
+
+  #[test]
+  fn test_backup_to() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"backed up".to_vec()))
+      .unwrap();
+    let backup = create_temp_file_name();
+    fs::write(&backup, b"previous backup").unwrap();
+    storage.backup_to(&backup).unwrap();
+    // The storage keeps working after its file was read from the start.
+    storage.claimBlock(1).unwrap();
+
+    let mut options = fs::File::options();
+    let file = options.read(true).write(true).open(&backup).unwrap();
+    let mut copy = BlockStorage::open(file).unwrap();
+    assert_eq!(copy.meta.offset, 3);
+    let read = copy.readBlockOffset(&block, 0, 9).unwrap();
+    assert_eq!(read, b"backed up".to_vec());
+  }
+
+  #[test]
+  fn test_load_into_memory() {
+    let mut storage = create_temp_storage();
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlock(&first, VecDeque::from(b"first".to_vec()))
+      .unwrap();
+    storage
+      .writeBlockOffset(&second, BLOCK_SIZE + 3, VecDeque::from(b"second".to_vec()))
+      .unwrap();
+
+    let mut memory = storage.load_into_memory().unwrap();
+    for block in [&first, &second] {
+      assert_eq!(
+        memory.readBlock(block).unwrap(),
+        storage.readBlock(block).unwrap()
+      );
+    }
+    assert_eq!(
+      memory.readBlockOffset(&second, BLOCK_SIZE + 3, 6).unwrap(),
+      b"second".to_vec()
+    );
+    assert!(memory
+      .writeBlock(&first, VecDeque::from(b"x".to_vec()))
+      .is_err());
+  }
+
+  #[test]
+  fn test_geometric_growth() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let storage_options = StorageOptions {
+      growth: GrowthPolicy::Geometric,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(file_name.clone()).unwrap(), storage_options)
+        .unwrap();
+    for _ in 0..1000 {
+      storage.claimBlock(1).unwrap();
+    }
+    // 2, 4, ..., 1024 blocks.
+    assert_eq!(storage.metrics().resizes, 10);
+    assert_eq!(storage.meta.offset, 1001);
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    let block = storage.claimBlock(20).unwrap();
+    assert_eq!(block.offset, 1001);
+    assert_eq!(storage.metrics().resizes, 0);
+
+    let mut exact = create_temp_storage();
+    for _ in 0..100 {
+      exact.claimBlock(1).unwrap();
+    }
+    assert_eq!(exact.metrics().resizes, 100);
+  }
+
+  #[test]
+  fn test_payload_alignment() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let storage_options = StorageOptions {
+      payload_alignment: 64,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(file_name.clone()).unwrap(), storage_options)
+        .unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    assert_eq!(block.payload_offset(), 64);
+    assert_eq!(block.payload_size(), BLOCK_SIZE - 64);
+    let position = block.offset * BLOCK_SIZE + block.payload_offset();
+    assert_eq!(position % 64, 0);
+
+    storage
+      .writeBlock(&block, VecDeque::from(b"aligned".to_vec()))
+      .unwrap();
+    let raw = storage.readData(position, 7).unwrap();
+    assert_eq!(raw, b"aligned".to_vec());
+    let read = storage.readBlock(&block).unwrap();
+    assert_eq!(read.len() as u64, block.payload_size());
+    let full = VecDeque::from(vec![0u8; block.payload_size() as usize + 1]);
+    assert!(storage.writeBlock(&block, full).is_err());
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name.clone()).unwrap()).unwrap();
+    assert_eq!(storage.claimBlock(1).unwrap().payload_offset(), 64);
+    assert_eq!(
+      BlockStorage::read_header(&file_name)
+        .unwrap()
+        .payload_alignment(),
+      64
+    );
+
+    let misaligned = StorageOptions {
+      payload_alignment: 48,
+      ..StorageOptions::default()
+    };
+    let file = open.open(create_temp_file_name()).unwrap();
+    assert!(BlockStorage::create_with_options(file, misaligned).is_err());
+  }
+
+  #[test]
+  fn test_sync_policy() {
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let file = open.open(create_temp_file_name()).unwrap();
+    let mut storage = BlockStorage::create_with_sync_policy(file, SyncPolicy::Writes(4)).unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    // Creating and claiming wrote the meta twice.
+    assert_eq!(storage.metrics().syncs, 0);
+    storage.writeBlock(&block, VecDeque::from(vec![1])).unwrap();
+    assert_eq!(storage.metrics().syncs, 0);
+    storage.writeBlock(&block, VecDeque::from(vec![2])).unwrap();
+    assert_eq!(storage.metrics().syncs, 1);
+    for _ in 0..8 {
+      storage.writeBlock(&block, VecDeque::from(vec![3])).unwrap();
+    }
+    assert_eq!(storage.metrics().syncs, 3);
+
+    let file = open.open(create_temp_file_name()).unwrap();
+    let policy = SyncPolicy::IntervalOrWrites(Duration::from_secs(3600), 1000);
+    let mut storage = BlockStorage::create_with_sync_policy(file, policy).unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    for _ in 0..100 {
+      storage.writeBlock(&block, VecDeque::from(vec![4])).unwrap();
+    }
+    assert_eq!(storage.metrics().syncs, 0);
+
+    let file = open.open(create_temp_file_name()).unwrap();
+    let policy = SyncPolicy::Interval(Duration::ZERO);
+    let mut storage = BlockStorage::create_with_sync_policy(file, policy).unwrap();
+    assert_eq!(storage.metrics().syncs, 1);
+    storage.claimBlock(1).unwrap();
+    assert_eq!(storage.metrics().syncs, 2);
+  }
+
+  #[test]
+  fn test_transform() {
+    struct Xor(u8);
+
+    impl Transform for Xor {
+      fn id(&self) -> u64 {
+        0x100 + self.0 as u64
+      }
+
+      fn on_write(&self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|byte| byte ^ self.0).collect()
+      }
+
+      fn on_read(&self, data: &[u8]) -> Vec<u8> {
+        self.on_write(data)
+      }
+    }
+
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let options = StorageOptions {
+      transform: Some(Box::new(Xor(0x5A))),
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(file_name.clone()).unwrap(), options).unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    let stored = storage.readBlockOffset(&block, 0, 4).unwrap();
+    assert_eq!(
+      Vec::from(stored),
+      vec![b'd' ^ 0x5A, b'a' ^ 0x5A, b't' ^ 0x5A, b'a' ^ 0x5A]
+    );
+    assert_eq!(
+      storage
+        .readBlock(&block)
+        .unwrap()
+        .range(0..4)
+        .copied()
+        .collect::<Vec<_>>(),
+      b"data"
+    );
+    assert_eq!(
+      BlockStorage::read_header(&file_name).unwrap().transform(),
+      0x15A
+    );
+
+    drop(storage);
+    let err = BlockStorage::open(open.open(file_name.clone()).unwrap())
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err =
+      BlockStorage::open_with_transform(open.open(file_name.clone()).unwrap(), Box::new(Xor(1)))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut storage =
+      BlockStorage::open_with_transform(open.open(file_name).unwrap(), Box::new(Xor(0x5A)))
+        .unwrap();
+    assert_eq!(
+      storage
+        .readBlock(&block)
+        .unwrap()
+        .range(0..4)
+        .copied()
+        .collect::<Vec<_>>(),
+      b"data"
+    );
+
+    let memory = storage.load_into_memory().unwrap();
+    assert_eq!(
+      memory.readBlock(&block).unwrap(),
+      storage.readBlock(&block).unwrap()
+    );
+    assert_eq!(
+      memory.readBlockOffset(&block, 0, 4).unwrap(),
+      storage.readBlockOffset(&block, 0, 4).unwrap()
+    );
+    assert_eq!(Vec::from(memory.readBlock(&block).unwrap())[..4], *b"data");
+  }
+
+  #[test]
+  fn test_read_values() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let values = [1, 0, 300, u64::MAX, 42];
+    storage
+      .writeBlock(&block, Encoder::encode_u64_slice(&values))
+      .unwrap();
+    assert_eq!(storage.read_values(&block).unwrap(), values);
+
+    let empty = storage.claimBlock(1).unwrap();
+    assert!(storage.read_values(&empty).unwrap().is_empty());
+  }
+
+  #[derive(Debug, PartialEq)]
+  struct Record {
+    id: u64,
+    parent: Option<u64>,
+    location: BlockRef,
+    tags: Vec<u64>,
+  }
+
+  impl Encode for Record {
+    fn encode(&self, buf: &mut VecDeque<u8>) {
+      self.id.encode(buf);
+      self.parent.encode(buf);
+      self.location.encode(buf);
+      self.tags.as_slice().encode(buf);
+    }
+  }
+
+  impl Decode for Record {
+    fn decode(data: &mut VecDeque<u8>) -> Result<Record, DecodeError> {
+      Ok(Record {
+        id: u64::decode(data)?,
+        parent: Option::decode(data)?,
+        location: BlockRef::decode(data)?,
+        tags: Vec::decode(data)?,
+      })
+    }
+  }
+
+  #[test]
+  fn test_records() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let record = Record {
+      id: 7,
+      parent: Some(300),
+      location: BlockRef::from(&block),
+      tags: vec![1, u64::MAX],
+    };
+    storage.write_record(&block, &record).unwrap();
+    assert_eq!(storage.read_record::<Record>(&block).unwrap(), record);
+
+    // A record cut short by its length fails instead of reading the padding.
+    storage
+      .writeBlockOffset(&block, 0, VecDeque::from(vec![5]))
+      .unwrap();
+    assert!(matches!(
+      storage.read_record::<Record>(&block),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_peek_claim() {
+    let mut storage = create_temp_storage();
+    let blocks = (0..4)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    let mut blocks = blocks.into_iter();
+    storage.freeBlock(blocks.nth(1).unwrap()).unwrap();
+    for count in [1, 2, 1, 3] {
+      let metrics = storage.metrics();
+      let end = storage.meta.offset;
+      let offset = storage.peek_claim(count);
+      assert_eq!(storage.metrics(), metrics);
+      assert_eq!(storage.meta.offset, end);
+      assert_eq!(storage.claimBlock(count).unwrap().offset, offset);
+    }
+  }
+
+  #[test]
+  fn test_fragmentation() {
+    let mut storage = create_temp_storage();
+    assert_eq!(storage.fragmentation(), 0.0);
+    let blocks = (0..8)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    let mut contiguous = create_temp_storage();
+    for block in blocks.into_iter().step_by(2) {
+      storage.freeBlock(block).unwrap();
+    }
+    assert_eq!(storage.fragmentation(), 1.0);
+
+    let blocks = (0..8)
+      .map(|_| contiguous.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    for block in blocks {
+      contiguous.freeBlock(block).unwrap();
+    }
+    assert_eq!(contiguous.fragmentation(), 1.0 / 8.0);
+  }
+
+  #[test]
+  fn test_copy_block_to() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(3).unwrap();
+    let data = (0..BLOCK_SIZE * 2 + 5)
+      .map(|i| (i % 251) as u8)
+      .collect::<Vec<_>>();
+    storage
+      .writeBlock(&block, VecDeque::from(data.clone()))
+      .unwrap();
+    let mut out = Vec::new();
+    let copied = storage.copy_block_to(&block, &mut out).unwrap();
+    assert_eq!(copied, block.payload_size());
+    assert_eq!(out, Vec::from(storage.readBlock(&block).unwrap()));
+    assert_eq!(&out[..data.len()], data.as_slice());
+    assert_eq!(storage.metrics().reads, 3 + 1);
+  }
+
+  #[test]
+  fn test_block_size() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let options = StorageOptions {
+      block_size: 512,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(file_name.clone()).unwrap(), options).unwrap();
+    let block = storage.claimBlock(2).unwrap();
+    assert_eq!(block.size(), 1024);
+    storage
+      .writeBlockOffset(&block, 600, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), 3 * 512);
+    assert_eq!(
+      BlockStorage::read_header(&file_name).unwrap().block_size(),
+      512
+    );
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name.clone()).unwrap()).unwrap();
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 3);
+    let read = storage.readBlockOffset(&block, 600, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+
+    drop(storage);
+    let err = BlockStorage::open_expecting(open.open(file_name.clone()).unwrap(), BLOCK_SIZE)
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(BlockStorage::open_expecting(open.open(file_name).unwrap(), 512).is_ok());
+
+    for block_size in [0, 256, 1000] {
+      let options = StorageOptions {
+        block_size,
+        ..StorageOptions::default()
+      };
+      let file = open.open(create_temp_file_name()).unwrap();
+      let err = BlockStorage::create_with_options(file, options)
+        .err()
+        .unwrap();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_create_path() {
+    let file_name = create_temp_file_name();
+    fs::write(&file_name, [0xFF; 100]).unwrap();
+    let mut storage = BlockStorage::create_path(&file_name).unwrap();
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+    drop(storage);
+    let storage = BlockStorage::open(
+      fs::File::options()
+        .read(true)
+        .write(true)
+        .open(&file_name)
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(storage.meta.offset, 2);
+  }
+
+  #[test]
+  fn test_last_block() {
+    let mut storage = create_temp_storage();
+    assert!(storage.last_block().is_none());
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(2).unwrap();
+    assert_eq!(storage.last_block().unwrap().offset, second.offset);
+    assert_eq!(storage.last_block().unwrap().size(), second.size());
+    storage
+      .writeBlock(&first, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    assert_eq!(storage.last_block().unwrap().offset, first.offset);
+    storage.freeBlock(second).unwrap();
+    assert_eq!(storage.last_block().unwrap().offset, first.offset);
+    storage.freeBlock(first).unwrap();
+    assert!(storage.last_block().is_none());
+
+    let third = storage.claimBlock(3).unwrap();
+    let third = storage.truncate_block(third, 1).unwrap();
+    assert_eq!(storage.last_block().unwrap().size(), third.size());
+  }
+
+  #[test]
+  fn test_io_timeout() {
+    let slow = with_timeout(Duration::from_millis(10), || {
+      std::thread::sleep(Duration::from_millis(500));
+      Ok(())
+    });
+    assert_eq!(slow.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    let fast = with_timeout(Duration::from_secs(10), || Ok(7));
+    assert_eq!(fast.unwrap(), 7);
+
+    let mut storage = create_temp_storage();
+    storage.set_io_timeout(Some(Duration::from_secs(10)));
+    let block = storage.claimBlock(1).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    let read = storage.readBlockOffset(&block, 0, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+
+    // Timed I/O goes to its own position, not the shared cursor, so an abandoned
+    // operation can't redirect later ones.
+    let other = storage.claimBlock(1).unwrap();
+    storage.file.seek(io::SeekFrom::Start(0)).unwrap();
+    storage
+      .writeBlock(&other, VecDeque::from(b"other".to_vec()))
+      .unwrap();
+    storage.readBlockOffset(&block, 0, 4).unwrap();
+    #[cfg(unix)]
+    assert_eq!(storage.file.stream_position().unwrap(), 0);
+    storage.set_io_timeout(None);
+    let read = storage.readBlockOffset(&other, 0, 5).unwrap();
+    assert_eq!(Vec::from(read), b"other");
+    let read = storage.readBlockOffset(&block, 0, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+  }
+
+  #[test]
+  fn test_iter_blocks_by_offset() {
+    let mut storage = create_temp_storage();
+    let mut blocks = (0..6)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    storage.freeBlock(blocks.remove(4)).unwrap();
+    storage.freeBlock(blocks.remove(1)).unwrap();
+    storage.freeBlock(blocks.remove(0)).unwrap();
+    let runs = storage
+      .iter_blocks_by_offset()
+      .map(|block| (block.offset, block.size() / BLOCK_SIZE))
+      .collect::<Vec<_>>();
+    assert_eq!(runs, vec![(3, 2), (6, 1)]);
+
+    storage.freeBlock(blocks.remove(2)).unwrap();
+    let offsets = storage
+      .iter_blocks_by_offset()
+      .map(|block| block.offset)
+      .collect::<Vec<_>>();
+    assert_eq!(offsets, vec![3]);
+  }
+
+  #[test]
+  fn test_file_lock() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    let err = BlockStorage::open(open.open(&file_name).unwrap())
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(matches!(
+      LeaflessError::from_io(&err),
+      Some(LeaflessError::Locked)
+    ));
+
+    drop(storage);
+    let storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    let read_only = fs::File::open(&file_name).unwrap();
+    let err = BlockStorage::open_read_only(read_only).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    drop(storage);
+
+    // Read-only handles share the file, but keep writers out.
+    let mut reader = BlockStorage::open_read_only(fs::File::open(&file_name).unwrap()).unwrap();
+    let other = BlockStorage::open_read_only(fs::File::open(&file_name).unwrap()).unwrap();
+    let err = BlockStorage::open(open.open(&file_name).unwrap())
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let err = reader.claimBlock(1).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(reader.meta.offset, 1);
+    drop((reader, other));
+    BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+  }
+
+  #[test]
+  fn test_diff() {
+    let mut storage = create_temp_storage();
+    let blocks = [1, 3, 1].map(|count| storage.claimBlock(count).unwrap());
+    for (i, block) in blocks.iter().enumerate() {
+      let data = vec![i as u8 + 1; block.size() as usize - 9];
+      storage.writeBlock(block, VecDeque::from(data)).unwrap();
+    }
+    let mut stream = Vec::new();
+    storage.stream_to(&mut stream).unwrap();
+    let file = create_temp_storage().file;
+    let mut backup = BlockStorage::load_from(file, &mut stream.as_slice()).unwrap();
+    assert!(storage.diff(&mut backup).unwrap().is_empty());
+
+    storage
+      .writeBlockOffset(&blocks[1], BLOCK_SIZE + 5, VecDeque::from(vec![0xEE]))
+      .unwrap();
+    assert_eq!(
+      storage.diff(&mut backup).unwrap(),
+      vec![BlockDiff::Changed(3)]
+    );
+
+    let [first, ..] = blocks;
+    storage.freeBlock(first).unwrap();
+    backup.claimBlock(1).unwrap();
+    assert_eq!(
+      storage.diff(&mut backup).unwrap(),
+      vec![
+        BlockDiff::OnlyInOther(1),
+        BlockDiff::Changed(3),
+        BlockDiff::OnlyInOther(6)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_retain() {
+    let mut storage = create_temp_storage();
+    let mut blocks = (0..5)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    for block in &blocks {
+      storage
+        .writeBlock(block, VecDeque::from(vec![block.offset as u8; 8]))
+        .unwrap();
+    }
+    storage.freeBlock(blocks.remove(3)).unwrap();
+    let runs = |storage: &BlockStorage| {
+      storage
+        .iter_blocks_by_offset()
+        .map(|block| (block.offset, block.size / BLOCK_SIZE))
+        .collect::<Vec<_>>()
+    };
+
+    // The three neighbouring claims are a single region, so none of them can be
+    // swept on its own; only the claim behind the free block is.
+    let mut seen = Vec::new();
+    storage
+      .retain(|block| {
+        seen.push((block.offset, block.size / BLOCK_SIZE));
+        block.offset != 5
+      })
+      .unwrap();
+    assert_eq!(seen, vec![(1, 3), (5, 1)]);
+    assert_eq!(runs(&storage), vec![(1, 3)]);
+
+    // Freeing a claim through its handle splits the run, which retain then sees.
+    storage.freeBlock(blocks.remove(1)).unwrap();
+    assert_eq!(runs(&storage), vec![(1, 1), (3, 1)]);
+    storage.retain(|block| block.offset == 3).unwrap();
+    assert_eq!(runs(&storage), vec![(3, 1)]);
+    assert_eq!(storage.meta.allocator.free_blocks(), 4);
+    assert_eq!(
+      Vec::from(storage.readBlockOffset(&blocks[1], 0, 8).unwrap()),
+      vec![3; 8]
+    );
+    assert_eq!(storage.claimBlock(2).unwrap().offset, 1);
+  }
+
+  #[test]
+  fn test_paranoid_checks() {
+    let mut storage = create_temp_storage().with_paranoid_checks(true);
+    let block = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlockOffset(&block, 100, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    let read = storage.readBlockOffset(&block, 100, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+    // The meta write of the claim and the block write were both read back.
+    assert_eq!(storage.metrics().reads, 3);
+  }
+
+  #[test]
+  fn test_buffer_pool() {
+    let mut storage = create_temp_storage().with_buffer_pool(2);
+    let block = storage.claimBlock(1).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(vec![0xAB; BLOCK_SIZE as usize]))
+      .unwrap();
+    assert_eq!(storage.buffer_pool.len(), 1);
+    let mut data = storage.readBlock(&block).unwrap();
+    assert!(storage.buffer_pool.is_empty());
+    for _ in 0..100 {
+      let ptr = data.as_slices().0.as_ptr();
+      storage.recycle_buffer(data);
+      data = storage.readBlock(&block).unwrap();
+      assert_eq!(data.as_slices().0.as_ptr(), ptr);
+    }
+    // A reused buffer holds only the fresh, shorter read.
+    storage.recycle_buffer(data);
+    let short = storage.readBlockOffset(&block, 0, 3).unwrap();
+    assert_eq!(Vec::from(short), vec![0xAB; 3]);
+
+    let reads = (0..4)
+      .map(|_| storage.readBlock(&block).unwrap())
+      .collect::<Vec<_>>();
+    reads
+      .into_iter()
+      .for_each(|buf| storage.recycle_buffer(buf));
+    assert_eq!(storage.buffer_pool.len(), 2);
+  }
+
+  #[test]
+  fn test_tagged_blocks() {
+    let mut storage = create_temp_storage();
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(2).unwrap();
+    storage.writeTaggedBlock(&first, 1, b"index").unwrap();
+    let payload = vec![0xAB; 5000];
+    storage.writeTaggedBlock(&second, 7, &payload).unwrap();
+    let (tag, data) = storage.readTaggedBlock(&first).unwrap();
+    assert_eq!((tag, Vec::from(data)), (1, b"index".to_vec()));
+    let (tag, data) = storage.readTaggedBlock(&second).unwrap();
+    assert_eq!((tag, Vec::from(data)), (7, payload.clone()));
+    // The CRC computed while copying matches a CRC over tag and payload at once.
+    let raw = Vec::from(storage.readBlock(&second).unwrap());
+    let header = TAGGED_MAGIC.len() + 1 + 2;
+    let mut tagged = vec![7];
+    tagged.extend(&payload);
+    assert_eq!(
+      raw[header..header + 4],
+      crc32fast::hash(&tagged).to_le_bytes()
+    );
+
+    storage
+      .writeBlockOffset(&first, 12, VecDeque::from(b"X".to_vec()))
+      .unwrap();
+    let err = storage.readTaggedBlock(&first).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let untagged = storage.claimBlock(1).unwrap();
+    let err = storage.readTaggedBlock(&untagged).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(storage
+      .writeTaggedBlock(&untagged, 0, &vec![0; BLOCK_SIZE as usize])
+      .is_err());
+  }
+
+  #[test]
+  fn test_corruption_handler() {
+    let mut storage = create_temp_storage();
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(1).unwrap();
+    storage.writeTaggedBlock(&first, 1, b"first").unwrap();
+    storage.writeTaggedBlock(&second, 2, b"second").unwrap();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let seen = events.clone();
+    storage.set_corruption_handler(Some(Box::new(move |event| seen.borrow_mut().push(*event))));
+    storage.readTaggedBlock(&first).unwrap();
+    assert!(events.borrow().is_empty());
+
+    // Flip a payload byte behind the 14 byte header.
+    storage
+      .writeBlockOffset(&second, 14, VecDeque::from(b"S".to_vec()))
+      .unwrap();
+    let err = storage.readTaggedBlock(&second).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(storage.readBlockChecked(&second).is_err());
+    let untagged = storage.claimBlock(1).unwrap();
+    assert!(storage.readTaggedBlock(&untagged).is_err());
+    let checksum = CorruptionEvent {
+      offset: 2,
+      kind: CorruptionKind::Checksum,
+    };
+    let magic = CorruptionEvent {
+      offset: 3,
+      kind: CorruptionKind::Magic,
+    };
+    assert_eq!(*events.borrow(), vec![checksum, checksum, magic]);
+  }
+
+  #[test]
+  fn test_read_block_checked() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    storage.writeTaggedBlock(&block, 3, b"record").unwrap();
+    let (tag, data) = storage.readBlockChecked(&block).unwrap();
+    assert_eq!((tag, Vec::from(data)), (3, b"record".to_vec()));
+
+    // The length varint follows the magic and tag.
+    storage
+      .writeBlockOffset(&block, 5, Encoder::encode_u64(BLOCK_SIZE))
+      .unwrap();
+    let err = storage.readBlockChecked(&block).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(
+      LeaflessError::from_io(&err),
+      Some(LeaflessError::Corrupted { offset: 1, .. })
+    ));
+
+    storage.freeBlock(block).unwrap();
+    let freed = storage.dataBlock(1, 1);
+    let err = storage.readBlockChecked(&freed).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn test_compact_into() {
+    let mut storage = create_temp_storage();
+    let blocks = [2, 1, 70, 1, 3].map(|count| storage.claimBlock(count).unwrap());
+    for (i, block) in blocks.iter().enumerate() {
+      let data = vec![i as u8 + 1; block.size() as usize - 3];
+      storage.writeBlock(block, VecDeque::from(data)).unwrap();
+    }
+    let refs = blocks.each_ref().map(BlockRef::from);
+    let [first, _, _, fourth, _] = blocks;
+    storage.freeBlock(first).unwrap();
+    storage.freeBlock(fourth).unwrap();
+
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let remap = storage
+      .compact_into(open.open(&file_name).unwrap())
+      .unwrap();
+    assert_eq!(remap.get(1), None);
+    assert_eq!(remap.get(74), None);
+    assert_eq!(remap.get(3), Some(1));
+    assert_eq!(remap.get(77), Some(74));
+    // The source is left as it was.
+    assert_eq!(storage.meta.offset, 78);
+
+    let mut compacted = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    assert_eq!(compacted.meta.offset, 75);
+    assert_eq!(compacted.meta.allocator.free_blocks(), 0);
+    for i in [1, 2, 4] {
+      let block = compacted
+        .block_from_ref(remap.remap(refs[i]).unwrap())
+        .unwrap();
+      let mut data = vec![i as u8 + 1; block.size() as usize - 3];
+      data.extend([0; 3]);
+      assert_eq!(Vec::from(compacted.readBlock(&block).unwrap()), data);
+    }
+  }
+
+  #[test]
+  fn test_raw_storage() {
+    let file_name = create_temp_file_name();
+    let meta_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create_raw(
+      open.open(&file_name).unwrap(),
+      open.open(&meta_name).unwrap(),
+    )
+    .unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    assert_eq!(block.offset, 0);
+    storage
+      .writeBlock(&block, VecDeque::from(b"raw".to_vec()))
+      .unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), BLOCK_SIZE);
+    let contents = fs::read(&file_name).unwrap();
+    assert_eq!(&contents[..3], b"raw");
+
+    drop(storage);
+    let mut storage = BlockStorage::open_raw(
+      open.open(&file_name).unwrap(),
+      open.open(&meta_name).unwrap(),
+    )
+    .unwrap();
+    assert!(storage.is_valid_block(&block));
+    let read = storage.readBlockOffset(&block, 0, 3).unwrap();
+    assert_eq!(Vec::from(read), b"raw");
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+
+    // A partial trailing block is grown over rather than handed out truncated.
+    drop(storage);
+    let file = open.open(&file_name).unwrap();
+    file.set_len(2 * BLOCK_SIZE + 100).unwrap();
+    let mut storage = BlockStorage::open_raw(file, open.open(&meta_name).unwrap()).unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    assert_eq!(block.offset, 2);
+    storage
+      .writeBlock(&block, VecDeque::from(vec![1; BLOCK_SIZE as usize]))
+      .unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), 3 * BLOCK_SIZE);
+  }
+
+  #[test]
+  fn test_checkpoint() {
+    let mut storage = create_temp_storage();
+    storage.claimBlock(2).unwrap();
+    let first = storage.checkpoint().unwrap();
+    assert_eq!(first.sequence, 0);
+    assert_eq!(first.offset, 3);
+    assert!(storage.blocks_since(first).is_empty());
+
+    let hole = storage.claimBlock(1).unwrap();
+    storage.claimBlock(3).unwrap();
+    storage.freeBlock(hole).unwrap();
+    let second = storage.checkpoint().unwrap();
+    assert!(second > first);
+    let blocks = storage
+      .blocks_since(first)
+      .iter()
+      .map(|block| (block.offset, block.size / BLOCK_SIZE))
+      .collect::<Vec<_>>();
+    assert_eq!(blocks, vec![(4, 3)]);
+    assert!(storage.blocks_since(second).is_empty());
+    assert_eq!(storage.metrics().syncs, 2);
+  }
+
+  #[test]
+  fn test_fill_byte() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let storage_options = StorageOptions {
+      fill_byte: 0xFF,
+      growth: GrowthPolicy::Geometric,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(&file_name).unwrap(), storage_options).unwrap();
+    let block = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(vec![0; 3]))
+      .unwrap();
+    let mut expected = vec![0xFF; block.size() as usize];
+    expected[..3].fill(0);
+    assert_eq!(Vec::from(storage.readBlock(&block).unwrap()), expected);
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    let block = storage.claimBlock(5).unwrap();
+    let data = storage.readBlock(&block).unwrap();
+    assert!(data.iter().all(|&byte| byte == 0xFF));
+  }
+
+  #[test]
+  fn test_read_until() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    let long = vec![b'x'; 600];
+    let mut data = b"first\nsecond\n".to_vec();
+    data.extend(&long);
+    data.push(b'\n');
+    storage.writeBlock(&block, VecDeque::from(data)).unwrap();
+
+    let mut offset = 0;
+    let mut records = Vec::new();
+    for _ in 0..3 {
+      let record = storage.read_until(&block, offset, b'\n', 1000).unwrap();
+      offset += record.len() as u64;
+      records.push(record);
+    }
+    assert_eq!(records[0], b"first\n");
+    assert_eq!(records[1], b"second\n");
+    assert_eq!(records[2].len(), 601);
+    assert_eq!(records[2][..600], long[..]);
+
+    let capped = storage.read_until(&block, 0, b'\n', 3).unwrap();
+    assert_eq!(capped, b"fir");
+    // Without a delimiter the read stops at the end of the block.
+    let rest = storage.read_until(&block, offset, b'\n', u64::MAX).unwrap();
+    assert_eq!(rest.len() as u64, BLOCK_SIZE - offset);
+    assert!(storage
+      .read_until(&block, BLOCK_SIZE, b'\n', 10)
+      .unwrap()
+      .is_empty());
+  }
+
+  #[test]
+  fn test_app_metadata() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    assert!(storage.app_metadata().is_empty());
+    let block = storage.claimBlock(2).unwrap();
+    storage.set_app_metadata(b"schema=3").unwrap();
+    // Free list changes rewrite the meta around the app metadata.
+    storage.freeBlock(block).unwrap();
+    storage.claimBlock(1).unwrap();
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    assert_eq!(storage.app_metadata(), b"schema=3");
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 2);
+    assert!(storage
+      .set_app_metadata(&vec![1; BLOCK_SIZE as usize])
+      .is_err());
+    assert_eq!(storage.app_metadata(), b"schema=3");
+  }
+
+  #[test]
+  fn test_bulk_load() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    storage.claimBlock(1).unwrap();
+    let item = |i: usize| vec![i as u8; i * 7 % 5000];
+    let blocks = storage
+      .bulk_load((0..1000).map(|i| VecDeque::from(item(i))))
+      .unwrap();
+    assert_eq!(blocks[0].offset, 2);
+    assert_eq!(storage.metrics().writes, 4);
+    let refs = blocks.iter().map(BlockRef::from).collect::<Vec<_>>();
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    let end = blocks.last().unwrap().offset + 1;
+    assert_eq!(storage.meta.offset, end);
+    for (i, block_ref) in refs.into_iter().enumerate() {
+      let block = storage.block_from_ref(block_ref).unwrap();
+      let data = item(i);
+      let read = storage
+        .readBlockOffset(&block, 0, data.len() as u64)
+        .unwrap();
+      assert_eq!(Vec::from(read), data);
+    }
+    assert_eq!(storage.claimBlock(1).unwrap().offset, end);
+  }
+
+  #[test]
+  fn test_split_block() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(4).unwrap();
+    assert!(storage.split_block(storage.dataBlock(1, 4), 0).is_err());
+    assert!(storage.split_block(storage.dataBlock(1, 4), 4).is_err());
+    let (index, data) = block.split_at(1);
+    assert_eq!((index.offset, index.size), (1, BLOCK_SIZE));
+    assert_eq!((data.offset, data.size), (2, 3 * BLOCK_SIZE));
+
+    storage
+      .writeBlock(&index, VecDeque::from(vec![1; BLOCK_SIZE as usize]))
+      .unwrap();
+    storage
+      .writeBlock(&data, VecDeque::from(vec![2; 3 * BLOCK_SIZE as usize]))
+      .unwrap();
+    assert!(storage.readBlock(&index).unwrap().iter().all(|&b| b == 1));
+    assert!(storage.readBlock(&data).unwrap().iter().all(|&b| b == 2));
+    storage.freeBlock(index).unwrap();
+    assert!(storage.is_valid_block(&data));
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+  }
+
+  #[test]
+  fn test_read_blocks() {
+    let mut storage = create_temp_storage();
+    let blocks = [1, 2, 1, 1, 3].map(|count| storage.claimBlock(count).unwrap());
+    for (i, block) in blocks.iter().enumerate() {
+      let data = vec![i as u8 + 1; 100 + i];
+      storage.writeBlock(block, VecDeque::from(data)).unwrap();
+    }
+    let [a, b, _, d, e] = &blocks;
+    let wanted = [e, a, d, b];
+    let reads = storage.read_blocks(&wanted).unwrap();
+    let before = storage.metrics().reads;
+    for (block, read) in wanted.iter().zip(&reads) {
+      assert_eq!(*read, storage.readBlock(block).unwrap());
+    }
+    assert_eq!(storage.metrics().reads - before, 4);
+    // Blocks 1-3 and 5-8 are read in one go each.
+    let reads = storage.metrics().reads;
+    storage.read_blocks(&wanted).unwrap();
+    assert_eq!(storage.metrics().reads - reads, 2);
+    assert!(storage.read_blocks(&[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_meta_checksum() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    let block = storage.claimBlock(3).unwrap();
+    storage.claimBlock(1).unwrap();
+    storage.freeBlock(block).unwrap();
+    let meta = storage.meta.serialize();
+    let last = meta.len() as u64 - 1;
+    drop(storage);
+
+    let mut file = open.open(&file_name).unwrap();
+    let mut contents = fs::read(&file_name).unwrap();
+    // Turn the free extent into (1, 4), overlapping the claimed block 4.
+    assert_eq!(contents[last as usize - 1], 3);
+    contents[last as usize - 1] = 4;
+    file.write_all(&contents[..meta.len()]).unwrap();
+    drop(file);
+    let err = BlockStorage::open(open.open(&file_name).unwrap())
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn test_set_meta_offset_raw() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    let block = storage.claimBlock(4).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"kept".to_vec()))
+      .unwrap();
+    assert!(storage.set_meta_offset_raw(0).is_err());
+    storage.set_meta_offset_raw(8).unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), 8 * BLOCK_SIZE);
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(&file_name).unwrap()).unwrap();
+    assert_eq!(storage.meta.offset, 8);
+    assert_eq!(
+      Vec::from(storage.readBlockOffset(&block, 0, 4).unwrap()),
+      b"kept"
+    );
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 8);
+  }
+
+  #[test]
+  fn test_compaction_policy() {
+    let mut storage = create_temp_storage();
+    let mut blocks = (0..10)
+      .map(|_| Some(storage.claimBlock(1).unwrap()))
+      .collect::<Vec<_>>();
+    let fired = Rc::new(RefCell::new(Vec::new()));
+    let seen = fired.clone();
+    let policy = CompactionPolicy {
+      fragmentation_threshold: 0.5,
+      min_free_blocks: 3,
+    };
+    storage.set_compaction_policy(Some((
+      policy,
+      Box::new(move |fragmentation, free| seen.borrow_mut().push((fragmentation, free))),
+    )));
+    for index in [0, 2] {
+      storage.freeBlock(blocks[index].take().unwrap()).unwrap();
+    }
+    assert!(fired.borrow().is_empty());
+    storage.freeBlock(blocks[4].take().unwrap()).unwrap();
+    assert_eq!(*fired.borrow(), vec![(1.0, 3)]);
+    // Still above the threshold, so no new call.
+    storage.freeBlock(blocks[6].take().unwrap()).unwrap();
+    assert_eq!(fired.borrow().len(), 1);
+
+    // Coalescing drops below the threshold and arms the policy again.
+    for index in [1, 3, 5] {
+      storage.freeBlock(blocks[index].take().unwrap()).unwrap();
+    }
+    assert_eq!(storage.fragmentation(), 1.0 / 7.0);
+    storage.freeBlock(blocks[8].take().unwrap()).unwrap();
+    assert_eq!(fired.borrow().len(), 1);
+
+    // Truncating frees blocks too.
+    let seen = fired.clone();
+    let policy = CompactionPolicy {
+      fragmentation_threshold: 0.2,
+      min_free_blocks: 3,
+    };
+    storage.set_compaction_policy(Some((
+      policy,
+      Box::new(move |fragmentation, free| seen.borrow_mut().push((fragmentation, free))),
+    )));
+    let block = storage.claimBlock(4).unwrap();
+    storage.truncate_block(block, 1).unwrap();
+    let expected = (
+      storage.fragmentation(),
+      storage.meta.allocator.free_blocks(),
+    );
+    assert_eq!(fired.borrow()[1..], [expected]);
+    storage.set_compaction_policy(None);
+    storage.freeBlock(blocks[9].take().unwrap()).unwrap();
+    assert_eq!(fired.borrow().len(), 2);
+  }
+
+  #[test]
+  fn test_relocate_block() {
+    let mut storage = create_temp_storage();
+    let hole = storage.claimBlock(3).unwrap();
+    let block = storage.claimBlock(2).unwrap();
+    let data = (0..BLOCK_SIZE + 10).map(|i| i as u8).collect::<Vec<_>>();
+    storage
+      .writeBlock(&block, VecDeque::from(data.clone()))
+      .unwrap();
+    storage.freeBlock(hole).unwrap();
+
+    let taken = DataBlock {
+      offset: block.offset,
+      size: block.size,
+      payload_offset: 0,
+      block_size: BLOCK_SIZE,
+    };
+    assert!(storage.relocate_block(taken, 3).is_err());
+    // An unclaimed source fails without taking the destination.
+    let stale = DataBlock {
+      offset: 1,
+      size: BLOCK_SIZE,
+      payload_offset: 0,
+      block_size: BLOCK_SIZE,
+    };
+    let err = storage.relocate_block(stale, 2).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(storage.meta.allocator.free_blocks(), 3);
+    let moved = storage.relocate_block(block, 2).unwrap();
+    assert_eq!(moved.offset, 2);
+    let read = storage
+      .readBlockOffset(&moved, 0, data.len() as u64)
+      .unwrap();
+    assert_eq!(Vec::from(read), data);
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 1);
+    assert_eq!(storage.claimBlock(2).unwrap().offset, 4);
+  }
+
+  #[test]
+  fn test_open_overgrown() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(&file_name).unwrap()).unwrap();
+    storage.claimBlock(2).unwrap();
+    drop(storage);
+    // As if a claim grew the file by three blocks and crashed before the meta flush.
+    let file = open.open(&file_name).unwrap();
+    file.set_len(6 * BLOCK_SIZE).unwrap();
+
+    let mut storage = BlockStorage::open(file).unwrap();
+    let block = storage.claimBlock(3).unwrap();
+    assert_eq!(block.offset, 3);
+    assert_eq!(storage.metrics().resizes, 0);
+    storage
+      .writeBlock(&block, VecDeque::from(vec![1; 3 * BLOCK_SIZE as usize]))
+      .unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), 6 * BLOCK_SIZE);
+
+    // A partial trailing block is grown over rather than handed out truncated.
+    drop(storage);
+    let file = open.open(&file_name).unwrap();
+    file.set_len(6 * BLOCK_SIZE + 100).unwrap();
+    let mut storage = BlockStorage::open(file).unwrap();
+    let block = storage.claimBlock(1).unwrap();
+    assert_eq!(block.offset, 6);
+    storage
+      .writeBlock(&block, VecDeque::from(vec![2; BLOCK_SIZE as usize]))
+      .unwrap();
+    assert_eq!(fs::metadata(&file_name).unwrap().len(), 7 * BLOCK_SIZE);
+  }
+
+  #[test]
+  fn test_open_at() {
+    let file_name = create_temp_file_name();
+    fs::write(&file_name, b"container header").unwrap();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true);
+    let options = StorageOptions {
+      base_offset: 100,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(open.open(&file_name).unwrap(), options).unwrap();
+    let block = storage.claimBlock(2).unwrap();
+    assert_eq!(block.offset, 1);
+    storage
+      .writeBlockOffset(&block, 5, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    assert_eq!(
+      fs::metadata(&file_name).unwrap().len(),
+      100 + 3 * BLOCK_SIZE
+    );
+
+    let contents = fs::read(&file_name).unwrap();
+    assert_eq!(&contents[..16], b"container header");
+    let position = 100 + BLOCK_SIZE as usize + 5;
+    assert_eq!(&contents[position..position + 4], b"data");
+
+    drop(storage);
+    let mut storage = BlockStorage::open_at(open.open(&file_name).unwrap(), 100).unwrap();
+    let read = storage.readBlockOffset(&block, 5, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+    assert_eq!(storage.claimBlock(1).unwrap().offset, 3);
+  }
+
+  #[test]
+  fn test_block_ref() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(2).unwrap();
+    storage
+      .writeBlock(&block, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+    let mut buf = VecDeque::new();
+    BlockRef::from(&block).encode(&mut buf);
+    assert_eq!(BlockRef::from(&block).encoded_size(), buf.len());
+    let block_ref = BlockRef::decode(&mut buf).unwrap();
+    assert_eq!(
+      block_ref,
+      BlockRef {
+        offset: 1,
+        size: 2 * BLOCK_SIZE
+      }
+    );
+    let resolved = storage.block_from_ref(block_ref).unwrap();
+    let read = storage.readBlockOffset(&resolved, 0, 4).unwrap();
+    assert_eq!(Vec::from(read), b"data");
+
+    for invalid in [
+      BlockRef {
+        offset: 1,
+        size: 3 * BLOCK_SIZE,
+      },
+      BlockRef {
+        offset: 0,
+        size: BLOCK_SIZE,
+      },
+      BlockRef {
+        offset: 1,
+        size: 10,
+      },
+    ] {
+      let err = storage.block_from_ref(invalid).err().unwrap();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    storage.freeBlock(block).unwrap();
+    assert!(storage.block_from_ref(block_ref).is_err());
+  }
+
+  #[test]
+  fn test_checksum_failure_handler() {
+    let mut storage = create_temp_storage();
+    let block = storage.claimBlock(1).unwrap();
+    storage.writeTaggedBlock(&block, 3, b"replicated").unwrap();
+    let replica = Vec::from(storage.readBlockOffset(&block, 0, 24).unwrap());
+    storage
+      .writeBlockOffset(&block, 16, VecDeque::from(b"XX".to_vec()))
+      .unwrap();
+    assert!(storage.readTaggedBlock(&block).is_err());
+
+    storage.set_checksum_failure_handler(Some(Box::new(|_| None)));
+    assert!(storage.readTaggedBlock(&block).is_err());
+    storage.set_checksum_failure_handler(Some(Box::new(move |_| Some(replica.clone()))));
+    let (tag, data) = storage.readTaggedBlock(&block).unwrap();
+    assert_eq!((tag, Vec::from(data)), (3, b"replicated".to_vec()));
+    storage.set_checksum_failure_handler(None);
+    let (_, data) = storage.readTaggedBlock(&block).unwrap();
+    assert_eq!(Vec::from(data), b"replicated");
+  }
+
+  #[test]
+  fn test_stream_to() {
+    let mut storage = create_temp_storage();
+    let blocks = [1, 2, 70, 1]
+      .map(|count| storage.claimBlock(count).unwrap())
+      .into_iter()
+      .collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+      let data = vec![i as u8 + 1; block.size() as usize - 7];
+      storage.writeBlock(block, VecDeque::from(data)).unwrap();
+    }
+    storage
+      .freeBlock(blocks.into_iter().nth(1).unwrap())
+      .unwrap();
+    let mut stream = Vec::new();
+    let written = storage.stream_to(&mut stream).unwrap();
+    assert_eq!(written, stream.len() as u64);
+    // Free blocks are skipped, so the stream is smaller than the claimed region.
+    assert!(stream.len() < 73 * BLOCK_SIZE as usize);
+
+    let file = create_temp_storage().file;
+    let mut loaded = BlockStorage::load_from(file, &mut stream.as_slice()).unwrap();
+    for (offset, count) in [(1, 1), (4, 70), (74, 1)] {
+      let block = DataBlock {
+        offset,
+        size: count * BLOCK_SIZE,
+        payload_offset: 0,
+        block_size: BLOCK_SIZE,
+      };
+      assert_eq!(
+        loaded.readBlock(&block).unwrap(),
+        storage.readBlock(&block).unwrap()
+      );
+    }
+    assert_eq!(loaded.claimBlock(2).unwrap().offset, 2);
+    assert_eq!(loaded.claimBlock(1).unwrap().offset, 75);
+
+    let file = create_temp_storage().file;
+    let truncated = &stream[..stream.len() - 10];
+    assert!(BlockStorage::load_from(file, &mut &truncated[..]).is_err());
+
+    // Free blocks come back holding the fill byte, not the zeros of the resize.
+    let options = StorageOptions {
+      fill_byte: 0xFF,
+      ..StorageOptions::default()
+    };
+    let mut storage =
+      BlockStorage::create_with_options(create_temp_storage().file, options).unwrap();
+    let blocks = (0..3)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    for block in &blocks {
+      storage
+        .writeBlock(block, VecDeque::from(b"data".to_vec()))
+        .unwrap();
+    }
+    storage
+      .freeBlock(blocks.into_iter().nth(1).unwrap())
+      .unwrap();
+    let mut stream = Vec::new();
+    storage.stream_to(&mut stream).unwrap();
+    let file = create_temp_storage().file;
+    let mut loaded = BlockStorage::load_from(file, &mut stream.as_slice()).unwrap();
+    let reused = loaded.claimBlock(1).unwrap();
+    assert_eq!(reused.offset, 2);
+    let data = loaded.readBlock(&reused).unwrap();
+    assert!(data.iter().all(|&byte| byte == 0xFF));
+  }
+
+  #[test]
+  fn test_write_beyond_file_end() {
+    let mut storage = create_temp_storage();
+    let first = storage.claimBlock(1).unwrap();
+    let second = storage.claimBlock(2).unwrap();
+    storage.file.set_len(2 * BLOCK_SIZE + 10).unwrap();
+    // The storage learns of the truncation from a read ending early.
+    assert_eq!(storage.readBlock(&second).unwrap().len(), 10);
+    let err = storage
+      .writeBlock(&second, VecDeque::from(b"data".to_vec()))
+      .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(storage.file.metadata().unwrap().len(), 2 * BLOCK_SIZE + 10);
+    storage
+      .writeBlock(&first, VecDeque::from(b"data".to_vec()))
+      .unwrap();
+  }
+
+  #[test]
+  fn test_write_barrier() {
+    let mut storage = create_temp_storage();
+    let data = storage.claimBlock(1).unwrap();
+    let index = storage.claimBlock(1).unwrap();
+    storage
+      .writeBlock(&data, VecDeque::from(b"value".to_vec()))
+      .unwrap();
+    assert_eq!(storage.metrics().syncs, 0);
+    storage.write_barrier().unwrap();
+    assert_eq!(storage.metrics().syncs, 1);
+    assert_eq!(storage.unsynced_writes, 0);
+    storage
+      .writeBlock(&index, Encoder::encode_u64(data.offset))
+      .unwrap();
+    assert_eq!(storage.metrics().syncs, 1);
+    assert_eq!(storage.unsynced_writes, 1);
+  }
+
+  #[test]
+  fn test_sequential_reads_skip_seeks() {
+    let mut storage = create_temp_storage();
+    let blocks = (0..4)
+      .map(|_| storage.claimBlock(1).unwrap())
+      .collect::<Vec<_>>();
+    let seeks = storage.metrics().seeks;
+    for block in &blocks {
+      storage.readBlock(block).unwrap();
+    }
+    assert_eq!(storage.metrics().seeks, seeks + 1);
+    storage.readBlock(&blocks[0]).unwrap();
+    assert_eq!(storage.metrics().seeks, seeks + 2);
+
+    // A write moves the cursor as well, and is only followed by a seek if needed.
+    storage
+      .writeBlock(&blocks[1], VecDeque::from(vec![7; BLOCK_SIZE as usize]))
+      .unwrap();
+    storage.readBlock(&blocks[2]).unwrap();
+    assert_eq!(storage.metrics().seeks, seeks + 2);
+    assert_eq!(storage.readBlock(&blocks[1]).unwrap()[0], 7);
+  }
+
+  #[cfg(feature = "tracing")]
+  #[test]
+  fn test_tracing_events() {
+    use std::sync::{Arc, Mutex};
+    use tracing::{field, span, Event, Metadata, Subscriber};
+
+    /// Records the u64 fields of every event.
+    struct Capture(Arc<Mutex<Vec<(&'static str, u64)>>>);
+    struct Fields<'a>(&'a mut Vec<(&'static str, u64)>);
+
+    impl field::Visit for Fields<'_> {
+      fn record_u64(&mut self, field: &field::Field, value: u64) {
+        self.0.push((field.name(), value));
+      }
+
+      fn record_debug(&mut self, _: &field::Field, _: &dyn std::fmt::Debug) {}
+    }
+
+    impl Subscriber for Capture {
+      fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+      }
+
+      fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+      }
+
+      fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+      fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+      fn event(&self, event: &Event<'_>) {
+        event.record(&mut Fields(&mut self.0.lock().unwrap()));
+      }
+
+      fn enter(&self, _: &span::Id) {}
+
+      fn exit(&self, _: &span::Id) {}
+    }
+
+    let mut storage = create_temp_storage();
+    storage.claimBlock(1).unwrap();
+    let fields = Arc::new(Mutex::new(Vec::new()));
+    let block =
+      tracing::subscriber::with_default(Capture(fields.clone()), || storage.claimBlock(3).unwrap());
+    let fields = fields.lock().unwrap();
+    assert!(fields.contains(&("offset", block.offset)));
+    assert!(fields.contains(&("count", 3)));
+  }
+
+  #[test]
+  fn test_free_list_persists() {
+    let file_name = create_temp_file_name();
+    let mut options = fs::File::options();
+    let open = options.read(true).write(true).create(true);
+    let mut storage = BlockStorage::create(open.open(file_name.clone()).unwrap()).unwrap();
+    let block = storage.claimBlock(3).unwrap();
+    storage.claimBlock(1).unwrap();
+    storage.freeBlock(block).unwrap();
+
+    drop(storage);
+    let mut storage = BlockStorage::open(open.open(file_name).unwrap()).unwrap();
+    assert_eq!(storage.claimBlock(3).unwrap().offset, 1);
   }
 }
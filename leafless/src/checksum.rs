@@ -0,0 +1,68 @@
+/// CRC-32 (IEEE) of tagged blocks, fed piecewise, e.g. chunk by chunk while the
+/// data is copied.
+pub use crc32fast::Hasher as Crc32;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hash; stable across platforms and releases.
+pub fn fnv1a64(data: &[u8]) -> u64 {
+  let mut hasher = Fnv1a64::new();
+  hasher.update(data);
+  hasher.finish()
+}
+
+/// [`fnv1a64`] fed piecewise, e.g. byte by byte while the data is copied, so the
+/// data needn't be contiguous or scanned a second time.
+pub struct Fnv1a64(u64);
+
+impl Fnv1a64 {
+  pub fn new() -> Fnv1a64 {
+    Fnv1a64(FNV_OFFSET_BASIS)
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    data.iter().for_each(|&byte| self.update_byte(byte));
+  }
+
+  pub fn update_byte(&mut self, byte: u8) {
+    self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+  }
+
+  pub fn finish(&self) -> u64 {
+    self.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{fnv1a64, Crc32, Fnv1a64};
+
+  #[test]
+  fn test_fnv1a64() {
+    assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a64(b"foobar"), 0x8594_4171_f739_67e8);
+  }
+
+  #[test]
+  fn test_crc32() {
+    assert_eq!(crc32fast::hash(b"123456789"), 0xCBF4_3926);
+    let data = (0..5000u32).map(|i| (i * 31) as u8).collect::<Vec<_>>();
+    let mut crc = Crc32::new();
+    for chunk in data.chunks(777) {
+      crc.update(chunk);
+    }
+    assert_eq!(crc.finalize(), crc32fast::hash(&data));
+  }
+
+  #[test]
+  fn test_streaming() {
+    let data = (0..5000u32).map(|i| (i * 31) as u8).collect::<Vec<_>>();
+    let mut hasher = Fnv1a64::new();
+    let (head, tail) = data.split_at(1234);
+    hasher.update(head);
+    tail.iter().for_each(|&byte| hasher.update_byte(byte));
+    assert_eq!(hasher.finish(), fnv1a64(&data));
+  }
+}
@@ -1,33 +1,557 @@
-use std::collections::VecDeque;
+use std::{
+  collections::VecDeque,
+  error::Error,
+  fmt,
+  io::{self, BufRead, Read, Write},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 pub struct Encoder {}
 pub struct Decoder {}
 
+/// A key and value decoded by [`Decoder::decode_kv_entries`].
+pub type KvEntry = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug)]
+pub enum DecodeError {
+  /// The input ended in the middle of a value.
+  UnexpectedEof,
+  /// A tag byte selected no known variant.
+  InvalidTag(u8),
+  /// Values were nested deeper than the [`DepthLimit`] allows.
+  DepthExceeded,
+  /// A decoded value doesn't fit the type it is decoded into.
+  Overflow,
+  /// A varint was longer than the minimal encoding of its value.
+  NonCanonical,
+  /// Reading the input failed.
+  Io(io::Error),
+}
+
+/// Types with a binary encoding that can be nested in other encodings.
+pub trait Encode {
+  fn encode(&self, buf: &mut VecDeque<u8>);
+
+  /// Number of bytes `encode` appends. The default encodes the value to measure it.
+  fn encoded_size(&self) -> usize {
+    let mut buf = VecDeque::new();
+    self.encode(&mut buf);
+    buf.len()
+  }
+}
+
+/// Decoding counterpart of [`Encode`], consuming the value from the front of `data`.
+pub trait Decode: Sized {
+  fn decode(data: &mut VecDeque<u8>) -> Result<Self, DecodeError>;
+}
+
+impl Encode for u64 {
+  fn encode(&self, buf: &mut VecDeque<u8>) {
+    buf.extend(Encoder::encode_u64(*self));
+  }
+
+  fn encoded_size(&self) -> usize {
+    Encoder::encoded_len(*self)
+  }
+}
+
+impl Decode for u64 {
+  fn decode(data: &mut VecDeque<u8>) -> Result<u64, DecodeError> {
+    Decoder::read_u64(data)
+  }
+}
+
+/// Nesting depth a recursive decode may still descend, see [`DecodeNested`].
+#[derive(Clone, Copy, Debug)]
+pub struct DepthLimit {
+  remaining: usize,
+}
+
+impl DepthLimit {
+  pub fn new(max_depth: usize) -> DepthLimit {
+    DepthLimit {
+      remaining: max_depth,
+    }
+  }
+
+  /// Returns the limit for a value nested one level deeper.
+  pub fn nested(self) -> Result<DepthLimit, DecodeError> {
+    match self.remaining.checked_sub(1) {
+      Some(remaining) => Ok(DepthLimit { remaining }),
+      None => Err(DecodeError::DepthExceeded),
+    }
+  }
+}
+
+/// Decoding of recursive types, which pass [`DepthLimit::nested`] to their children
+/// so corrupt input can't nest them without bound.
+pub trait DecodeNested: Sized {
+  fn decode_nested(data: &mut VecDeque<u8>, limit: DepthLimit) -> Result<Self, DecodeError>;
+}
+
+const TAG_NONE: u8 = 0;
+const TAG_SOME: u8 = 1;
+
+/// Encoded as a presence byte, followed by the value if there is one.
+impl<T: Encode> Encode for Option<T> {
+  fn encode(&self, buf: &mut VecDeque<u8>) {
+    match self {
+      None => buf.push_back(TAG_NONE),
+      Some(value) => {
+        buf.push_back(TAG_SOME);
+        value.encode(buf);
+      }
+    }
+  }
+
+  fn encoded_size(&self) -> usize {
+    1 + self.as_ref().map_or(0, T::encoded_size)
+  }
+}
+
+impl<T: Decode> Decode for Option<T> {
+  fn decode(data: &mut VecDeque<u8>) -> Result<Option<T>, DecodeError> {
+    match data.pop_front() {
+      Some(TAG_NONE) => Ok(None),
+      Some(TAG_SOME) => Ok(Some(T::decode(data)?)),
+      Some(tag) => Err(DecodeError::InvalidTag(tag)),
+      None => Err(DecodeError::UnexpectedEof),
+    }
+  }
+}
+
+const TAG_IPV4: u8 = 4;
+const TAG_IPV6: u8 = 6;
+
+const TAG_OK: u8 = 0;
+const TAG_ERR: u8 = 1;
+
+/// Encoded as a tag byte selecting the arm, followed by that arm's value.
+impl<T: Encode, E: Encode> Encode for Result<T, E> {
+  fn encode(&self, buf: &mut VecDeque<u8>) {
+    match self {
+      Ok(value) => {
+        buf.push_back(TAG_OK);
+        value.encode(buf);
+      }
+      Err(err) => {
+        buf.push_back(TAG_ERR);
+        err.encode(buf);
+      }
+    }
+  }
+
+  fn encoded_size(&self) -> usize {
+    1 + match self {
+      Ok(value) => value.encoded_size(),
+      Err(err) => err.encoded_size(),
+    }
+  }
+}
+
+impl<T: Decode, E: Decode> Decode for Result<T, E> {
+  fn decode(data: &mut VecDeque<u8>) -> Result<Result<T, E>, DecodeError> {
+    match data.pop_front() {
+      Some(TAG_OK) => Ok(Ok(T::decode(data)?)),
+      Some(TAG_ERR) => Ok(Err(E::decode(data)?)),
+      Some(tag) => Err(DecodeError::InvalidTag(tag)),
+      None => Err(DecodeError::UnexpectedEof),
+    }
+  }
+}
+
+/// Encoded as the element count, followed by the elements.
+impl<T: Encode> Encode for [T] {
+  fn encode(&self, buf: &mut VecDeque<u8>) {
+    (self.len() as u64).encode(buf);
+    for value in self {
+      value.encode(buf);
+    }
+  }
+
+  fn encoded_size(&self) -> usize {
+    let values = self.iter().map(T::encoded_size).sum::<usize>();
+    Encoder::encoded_len(self.len() as u64) + values
+  }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+  fn decode(data: &mut VecDeque<u8>) -> Result<Vec<T>, DecodeError> {
+    let len = u64::decode(data)?;
+    // Every element takes at least a byte, which caps a corrupt count.
+    let mut values = Vec::with_capacity(len.min(data.len() as u64) as usize);
+    for _ in 0..len {
+      values.push(T::decode(data)?);
+    }
+    Ok(values)
+  }
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DecodeError::UnexpectedEof => write!(f, "Unexpected end of input"),
+      DecodeError::InvalidTag(tag) => write!(f, "Invalid tag {}", tag),
+      DecodeError::DepthExceeded => write!(f, "Nesting exceeds the depth limit"),
+      DecodeError::Overflow => write!(f, "Value exceeds the target type"),
+      DecodeError::NonCanonical => write!(f, "Varint is not minimally encoded"),
+      DecodeError::Io(err) => write!(f, "Failed to read input: {}", err),
+    }
+  }
+}
+
+impl Error for DecodeError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      DecodeError::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for DecodeError {
+  fn from(err: io::Error) -> DecodeError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+      DecodeError::UnexpectedEof
+    } else {
+      DecodeError::Io(err)
+    }
+  }
+}
+
+/// Iterator over the records of a stream, see [`Decoder::records`].
+pub struct Records<R, F> {
+  reader: io::BufReader<R>,
+  decode: F,
+  done: bool,
+}
+
+impl<R, T, F> Iterator for Records<R, F>
+where
+  R: Read,
+  F: FnMut(&mut io::BufReader<R>) -> Result<T, DecodeError>,
+{
+  type Item = Result<T, DecodeError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let result = match self.reader.fill_buf() {
+      Ok([]) => {
+        self.done = true;
+        return None;
+      }
+      Ok(_) => (self.decode)(&mut self.reader),
+      Err(err) => Err(err.into()),
+    };
+    self.done = result.is_err();
+    Some(result)
+  }
+}
+
+/// A varint wire format, for the `_with` variants of the encoding helpers and
+/// the varints a [`BlockStorage`](crate::block_storage::BlockStorage) writes.
+pub trait VarintCodec {
+  /// Identifies the scheme in a storage header, so a file isn't read with the
+  /// wrong one.
+  const ID: u64;
+
+  fn encode(value: u64, buf: &mut VecDeque<u8>);
+
+  /// Reads one varint from `reader`, failing if it ends before the value does.
+  fn read<R: Read>(reader: &mut R) -> Result<u64, DecodeError>;
+
+  fn decode(data: &mut VecDeque<u8>) -> Result<u64, DecodeError> {
+    Self::read(data)
+  }
+}
+
+/// The varint of [`Encoder::encode_u64`] and every format of this crate: 7 bits
+/// per byte with a continuation bit, except a 9th byte holding the top 8 bits.
+pub struct LeaflessVarint;
+
+impl VarintCodec for LeaflessVarint {
+  const ID: u64 = 0;
+
+  fn encode(value: u64, buf: &mut VecDeque<u8>) {
+    let (bytes, len) = Encoder::varint(value);
+    buf.extend(&bytes[..len]);
+  }
+
+  fn read<R: Read>(reader: &mut R) -> Result<u64, DecodeError> {
+    Decoder::read_u64(reader)
+  }
+}
+
+/// Standard unsigned LEB128, as used by protobuf: 7 bits per byte with a
+/// continuation bit throughout, so up to 10 bytes. Matches [`LeaflessVarint`]
+/// below `1 << 63`.
+pub struct Leb128;
+
+/// Most bytes of a LEB128 encoded u64.
+const LEB128_MAX_LEN: u64 = 10;
+
+impl VarintCodec for Leb128 {
+  const ID: u64 = 1;
+
+  fn encode(mut value: u64, buf: &mut VecDeque<u8>) {
+    while value > SEVEN_BITS {
+      buf.push_back((value & SEVEN_BITS) as u8 | HAS_NEXT);
+      value >>= BITS_PER_BYTE;
+    }
+    buf.push_back(value as u8);
+  }
+
+  /// Fails with [`DecodeError::Overflow`] if the value exceeds 64 bits.
+  fn read<R: Read>(reader: &mut R) -> Result<u64, DecodeError> {
+    let mut value: u64 = 0;
+    for i in 0..LEB128_MAX_LEN {
+      let byte = Decoder::read_byte(reader)?;
+      let bits = (byte & SEVEN_BITS as u8) as u64;
+      if i == LEB128_MAX_LEN - 1 && (bits > 1 || byte & HAS_NEXT != 0) {
+        return Err(DecodeError::Overflow);
+      }
+      value |= bits << (BITS_PER_BYTE * i);
+      if byte & HAS_NEXT == 0 {
+        return Ok(value);
+      }
+    }
+    unreachable!("the last byte either ends the value or overflows")
+  }
+}
+
+/// Values below this fit the first byte of [`Encoder::encode_tagged`].
+const TAGGED_INLINE_LIMIT: u64 = 16;
+const TAGGED_OVERFLOW: u8 = 0b0001_0000;
+
+/// Values sharing a control byte in [`Encoder::encode_group_varint`].
+const GROUP_SIZE: usize = 4;
+
 const BITS_PER_BYTE: u64 = 7;
 const SEVEN_BYTES_ONE_BIT: u64 = 0xFF_FF_FF_FF_FF_FF_FF_00 + 0b1000_0000;
 const SEVEN_BITS: u64 = 0b0111_1111;
 const HAS_NEXT: u8 = 0b1000_0000;
 
 impl Encoder {
-  pub fn encode_u64(mut value: u64) -> VecDeque<u8> {
-    let mut buf = VecDeque::<u8>::with_capacity(9);
+  pub fn encode_u64(value: u64) -> VecDeque<u8> {
+    let (bytes, len) = Encoder::varint(value);
+    bytes[..len].iter().copied().collect()
+  }
+
+  /// Encodes a 3-bit `tag` together with `value`, in one byte if `value < 16`.
+  ///
+  /// The first byte holds the tag in bits 7-5, an overflow flag in bit 4 and the
+  /// low 4 bits of `value` in bits 3-0. If `value` doesn't fit, the flag is set and
+  /// the first byte is followed by the varint of `value >> 4`.
+  ///
+  /// Panics if `tag` doesn't fit 3 bits.
+  pub fn encode_tagged(tag: u8, value: u64) -> VecDeque<u8> {
+    assert!(tag < 8, "Tag {} exceeds 3 bits", tag);
+    let low = (value % TAGGED_INLINE_LIMIT) as u8;
+    if value < TAGGED_INLINE_LIMIT {
+      return VecDeque::from(vec![tag << 5 | low]);
+    }
+    let mut buf = Encoder::encode_u64(value >> 4);
+    buf.push_front(tag << 5 | TAGGED_OVERFLOW | low);
+    buf
+  }
+
+  /// Encodes `values` as group varints: the value count as a varint, then groups of
+  /// four values, each a control byte followed by the values little-endian.
+  ///
+  /// Bits `2i..2i + 2` of the control byte give the width of the `i`th value of the
+  /// group as `1 << bits` bytes, so 1, 2, 4 or 8. The last group may hold fewer
+  /// values; its unused control bits are 0.
+  pub fn encode_group_varint(values: &[u64]) -> VecDeque<u8> {
+    let mut buf = Encoder::encode_u64(values.len() as u64);
+    for group in values.chunks(GROUP_SIZE) {
+      let mut control = 0;
+      let mut bytes = Vec::with_capacity(GROUP_SIZE * 8);
+      for (i, &value) in group.iter().enumerate() {
+        let code = match value {
+          0..=0xFF => 0,
+          0x100..=0xFFFF => 1,
+          0x1_0000..=0xFFFF_FFFF => 2,
+          _ => 3,
+        };
+        control |= code << (2 * i);
+        bytes.extend_from_slice(&value.to_le_bytes()[..1 << code]);
+      }
+      buf.push_back(control);
+      buf.extend(bytes);
+    }
+    buf
+  }
+
+  /// Number of bytes [`Encoder::encode_u64`] produces for `value`.
+  pub fn encoded_len(value: u64) -> usize {
+    match value {
+      // The 9th byte carries 8 bits instead of 7.
+      0..=0x00FF_FFFF_FFFF_FFFF => (64 - value.leading_zeros() as usize).max(1).div_ceil(7),
+      _ => 9,
+    }
+  }
+
+  /// Writes the varint encoding of `value` to `out` without allocating.
+  pub fn write_u64<W: Write>(value: u64, out: &mut W) -> io::Result<()> {
+    let (bytes, len) = Encoder::varint(value);
+    out.write_all(&bytes[..len])
+  }
+
+  /// Encodes `value` like [`Encoder::encode_u64`] into a stack buffer, without
+  /// allocating. The encoding is the first `len` bytes of the returned array.
+  pub fn encode_u64_array(value: u64) -> ([u8; 10], usize) {
+    let (bytes, len) = Encoder::varint(value);
+    let mut array = [0u8; 10];
+    array[..9].copy_from_slice(&bytes);
+    (array, len)
+  }
+
+  fn varint(mut value: u64) -> ([u8; 9], usize) {
+    let mut bytes = [0u8; 9];
+    let mut len = 0;
     for _ in 0..8 {
       let mut byte = (value & SEVEN_BITS) as u8;
-      value = value & SEVEN_BYTES_ONE_BIT;
+      value &= SEVEN_BYTES_ONE_BIT;
       value >>= BITS_PER_BYTE;
       if value != 0 {
         byte |= HAS_NEXT;
       }
-      buf.push_back(byte);
+      bytes[len] = byte;
+      len += 1;
       if value == 0 {
         break;
       }
     }
     if value != 0 {
-      let byte: u8 = (value & 0xFF) as u8;
-      buf.push_back(byte);
+      bytes[len] = (value & 0xFF) as u8;
+      len += 1;
     }
+    (bytes, len)
+  }
+
+  /// Encodes `value` as a u64, so the encoding is the same on every platform.
+  pub fn encode_usize(value: usize) -> VecDeque<u8> {
+    Encoder::encode_u64(value as u64)
+  }
+
+  pub fn encode_option_u64(value: Option<u64>) -> VecDeque<u8> {
+    let mut buf = VecDeque::with_capacity(10);
+    value.encode(&mut buf);
+    buf
+  }
 
+  /// Encodes either arm of a two-variant union, see [`Decoder::decode_either`].
+  pub fn encode_either<L: Encode, R: Encode>(value: &Result<L, R>) -> VecDeque<u8> {
+    let mut buf = VecDeque::with_capacity(value.encoded_size());
+    value.encode(&mut buf);
+    buf
+  }
+
+  /// Encodes exactly the `N` bytes of `value`, without a length prefix.
+  pub fn encode_fixed<const N: usize>(value: &[u8; N]) -> VecDeque<u8> {
+    VecDeque::from(value.to_vec())
+  }
+
+  /// Encodes `value` as exactly 8 little-endian bytes, for byte-exact interop with
+  /// external formats. Unlike big-endian bytes these don't sort lexicographically
+  /// in numeric order, so don't use them in keys compared as bytes.
+  pub fn encode_u64_le_fixed(value: u64) -> VecDeque<u8> {
+    Encoder::encode_fixed(&value.to_le_bytes())
+  }
+
+  /// Encodes `value` as exactly 4 little-endian bytes, see
+  /// [`Encoder::encode_u64_le_fixed`].
+  pub fn encode_u32_le_fixed(value: u32) -> VecDeque<u8> {
+    Encoder::encode_fixed(&value.to_le_bytes())
+  }
+
+  /// Encodes `entries` as a count followed by length-prefixed keys and varint
+  /// values, in the given order.
+  pub fn encode_map(entries: &[(&[u8], u64)]) -> VecDeque<u8> {
+    Encoder::encode_map_with::<LeaflessVarint>(entries)
+  }
+
+  /// Encodes `entries` like [`Encoder::encode_map`], with varints in the scheme `C`.
+  pub fn encode_map_with<C: VarintCodec>(entries: &[(&[u8], u64)]) -> VecDeque<u8> {
+    let mut buf = Encoder::encode_u64_with::<C>(entries.len() as u64);
+    for &(key, value) in entries {
+      C::encode(key.len() as u64, &mut buf);
+      buf.extend(key);
+      C::encode(value, &mut buf);
+    }
+    buf
+  }
+
+  /// Encodes `entries` as a count followed by length-prefixed keys and values,
+  /// see [`Decoder::decode_kv_entries`].
+  pub fn encode_kv_entries(entries: &[(&[u8], &[u8])]) -> VecDeque<u8> {
+    Encoder::encode_kv_entries_with::<LeaflessVarint>(entries)
+  }
+
+  /// Encodes `entries` like [`Encoder::encode_kv_entries`], with varints in the
+  /// scheme `C`.
+  pub fn encode_kv_entries_with<C: VarintCodec>(entries: &[(&[u8], &[u8])]) -> VecDeque<u8> {
+    let mut buf = Encoder::encode_u64_with::<C>(entries.len() as u64);
+    for &(key, value) in entries {
+      for bytes in [key, value] {
+        C::encode(bytes.len() as u64, &mut buf);
+        buf.extend(bytes);
+      }
+    }
+    buf
+  }
+
+  /// Encodes the 4 octets of `addr`.
+  pub fn encode_ipv4(addr: Ipv4Addr) -> VecDeque<u8> {
+    Encoder::encode_fixed(&addr.octets())
+  }
+
+  /// Encodes the 16 octets of `addr`.
+  pub fn encode_ipv6(addr: Ipv6Addr) -> VecDeque<u8> {
+    Encoder::encode_fixed(&addr.octets())
+  }
+
+  /// Encodes `addr` as a tag byte of 4 or 6 for the address family, the address,
+  /// then the port as a varint. The flow info and scope id of IPv6 are dropped.
+  pub fn encode_socket_addr(addr: SocketAddr) -> VecDeque<u8> {
+    let mut buf = match addr.ip() {
+      IpAddr::V4(ip) => {
+        let mut buf = Encoder::encode_ipv4(ip);
+        buf.push_front(TAG_IPV4);
+        buf
+      }
+      IpAddr::V6(ip) => {
+        let mut buf = Encoder::encode_ipv6(ip);
+        buf.push_front(TAG_IPV6);
+        buf
+      }
+    };
+    buf.extend(Encoder::encode_u64(addr.port() as u64));
+    buf
+  }
+
+  /// Encodes `values` length-prefixed, see [`Decoder::decode_u64_slice`].
+  pub fn encode_u64_slice(values: &[u64]) -> VecDeque<u8> {
+    let mut buf = VecDeque::with_capacity(values.len() + 1);
+    values.encode(&mut buf);
+    buf
+  }
+
+  /// Encodes `value` with the varint scheme `C`, see [`VarintCodec`].
+  pub fn encode_u64_with<C: VarintCodec>(value: u64) -> VecDeque<u8> {
+    let mut buf = VecDeque::new();
+    C::encode(value, &mut buf);
+    buf
+  }
+
+  /// Encodes `values` like [`Encoder::encode_u64_slice`], count and values in the
+  /// varint scheme `C`.
+  pub fn encode_u64_slice_with<C: VarintCodec>(values: &[u64]) -> VecDeque<u8> {
+    let mut buf = VecDeque::with_capacity(values.len() + 1);
+    C::encode(values.len() as u64, &mut buf);
+    values.iter().for_each(|&value| C::encode(value, &mut buf));
     buf
   }
 }
@@ -48,15 +572,299 @@ impl Decoder {
       }
     }
     if let Some(byte) = data.pop_front() {
-      value += (byte as u64) << BITS_PER_BYTE * 8;
+      value += (byte as u64) << (BITS_PER_BYTE * 8);
     }
     value
   }
+
+  /// Decodes a value written by [`Encoder::encode_usize`], failing with
+  /// [`DecodeError::Overflow`] if it exceeds `usize::MAX` on this platform.
+  pub fn decode_usize(data: &mut VecDeque<u8>) -> Result<usize, DecodeError> {
+    usize::try_from(Decoder::read_u64(data)?).map_err(|_| DecodeError::Overflow)
+  }
+
+  /// Decodes a varint like [`Decoder::read_u64`], failing with
+  /// [`DecodeError::NonCanonical`] unless it is the one [`Encoder::encode_u64`]
+  /// writes, e.g. where encodings are hashed.
+  pub fn decode_u64_canonical(data: &mut VecDeque<u8>) -> Result<u64, DecodeError> {
+    let len = data.len();
+    let value = Decoder::read_u64(data)?;
+    if len - data.len() != Encoder::encoded_len(value) {
+      return Err(DecodeError::NonCanonical);
+    }
+    Ok(value)
+  }
+
+  pub fn decode_option_u64(data: &mut VecDeque<u8>) -> Result<Option<u64>, DecodeError> {
+    Option::<u64>::decode(data)
+  }
+
+  /// Decodes a value written by [`Encoder::encode_either`], failing with
+  /// [`DecodeError::InvalidTag`] if the tag selects neither arm.
+  pub fn decode_either<L: Decode, R: Decode>(
+    data: &mut VecDeque<u8>,
+  ) -> Result<Result<L, R>, DecodeError> {
+    Result::<L, R>::decode(data)
+  }
+
+  /// Decodes a recursive value nested at most `max_depth` levels deep.
+  pub fn decode_nested<T: DecodeNested>(
+    data: &mut VecDeque<u8>,
+    max_depth: usize,
+  ) -> Result<T, DecodeError> {
+    T::decode_nested(data, DepthLimit::new(max_depth))
+  }
+
+  /// Decodes `N` bytes written by [`Encoder::encode_fixed`].
+  pub fn decode_fixed<const N: usize>(data: &mut VecDeque<u8>) -> Result<[u8; N], DecodeError> {
+    if data.len() < N {
+      return Err(DecodeError::UnexpectedEof);
+    }
+    let mut value = [0u8; N];
+    for (byte, decoded) in value.iter_mut().zip(data.drain(..N)) {
+      *byte = decoded;
+    }
+    Ok(value)
+  }
+
+  /// Decodes 8 bytes written by [`Encoder::encode_u64_le_fixed`].
+  pub fn decode_u64_le_fixed(data: &mut VecDeque<u8>) -> Result<u64, DecodeError> {
+    Ok(u64::from_le_bytes(Decoder::decode_fixed(data)?))
+  }
+
+  /// Decodes 4 bytes written by [`Encoder::encode_u32_le_fixed`].
+  pub fn decode_u32_le_fixed(data: &mut VecDeque<u8>) -> Result<u32, DecodeError> {
+    Ok(u32::from_le_bytes(Decoder::decode_fixed(data)?))
+  }
+
+  /// Parses the entries written by [`Encoder::encode_kv_entries`] from a raw block,
+  /// e.g. for inspection tools. Bytes behind the last entry, such as padding, are
+  /// ignored; a length reaching past the end of `data` is an error.
+  pub fn decode_kv_entries(data: &[u8]) -> Result<Vec<KvEntry>, DecodeError> {
+    Decoder::decode_kv_entries_with::<LeaflessVarint>(data)
+  }
+
+  /// Parses entries written by [`Encoder::encode_kv_entries_with`].
+  pub fn decode_kv_entries_with<C: VarintCodec>(
+    mut data: &[u8],
+  ) -> Result<Vec<KvEntry>, DecodeError> {
+    let count = C::read(&mut data)?;
+    // Every entry takes at least two bytes, which caps a corrupt count.
+    let mut entries = Vec::with_capacity(count.min(data.len() as u64 / 2) as usize);
+    for _ in 0..count {
+      let key = Decoder::read_len_prefixed::<C>(&mut data)?;
+      entries.push((key, Decoder::read_len_prefixed::<C>(&mut data)?));
+    }
+    Ok(entries)
+  }
+
+  fn read_len_prefixed<C: VarintCodec>(data: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let len = C::read(data)?;
+    if len > data.len() as u64 {
+      return Err(DecodeError::UnexpectedEof);
+    }
+    let (bytes, rest) = data.split_at(len as usize);
+    *data = rest;
+    Ok(bytes.to_vec())
+  }
+
+  pub fn decode_ipv4(data: &mut VecDeque<u8>) -> Result<Ipv4Addr, DecodeError> {
+    Ok(Ipv4Addr::from(Decoder::decode_fixed::<4>(data)?))
+  }
+
+  pub fn decode_ipv6(data: &mut VecDeque<u8>) -> Result<Ipv6Addr, DecodeError> {
+    Ok(Ipv6Addr::from(Decoder::decode_fixed::<16>(data)?))
+  }
+
+  /// Decodes an address written by [`Encoder::encode_socket_addr`], failing with
+  /// [`DecodeError::InvalidTag`] for an unknown address family and with
+  /// [`DecodeError::Overflow`] for a port above 65535.
+  pub fn decode_socket_addr(data: &mut VecDeque<u8>) -> Result<SocketAddr, DecodeError> {
+    let ip = match data.pop_front() {
+      Some(TAG_IPV4) => IpAddr::V4(Decoder::decode_ipv4(data)?),
+      Some(TAG_IPV6) => IpAddr::V6(Decoder::decode_ipv6(data)?),
+      Some(tag) => return Err(DecodeError::InvalidTag(tag)),
+      None => return Err(DecodeError::UnexpectedEof),
+    };
+    let port = u16::try_from(Decoder::read_u64(data)?).map_err(|_| DecodeError::Overflow)?;
+    Ok(SocketAddr::new(ip, port))
+  }
+
+  /// Decodes values written by [`Encoder::encode_group_varint`].
+  pub fn decode_group_varint(data: &mut VecDeque<u8>) -> Result<Vec<u64>, DecodeError> {
+    let len = Decoder::read_u64(data)?;
+    // Every value takes at least a byte, which caps a corrupt count.
+    let mut values = Vec::with_capacity(len.min(data.len() as u64) as usize);
+    while (values.len() as u64) < len {
+      let control = data.pop_front().ok_or(DecodeError::UnexpectedEof)?;
+      let group = (len - values.len() as u64).min(GROUP_SIZE as u64);
+      for i in 0..group {
+        let width = 1 << ((control >> (2 * i)) & 0b11);
+        if data.len() < width {
+          return Err(DecodeError::UnexpectedEof);
+        }
+        let mut bytes = [0u8; 8];
+        for (byte, value) in bytes.iter_mut().zip(data.drain(..width)) {
+          *byte = value;
+        }
+        values.push(u64::from_le_bytes(bytes));
+      }
+    }
+    Ok(values)
+  }
+
+  /// Decodes a tag and value written by [`Encoder::encode_tagged`], failing with
+  /// [`DecodeError::Overflow`] if the value exceeds 64 bits.
+  pub fn decode_tagged(data: &mut VecDeque<u8>) -> Result<(u8, u64), DecodeError> {
+    let first = data.pop_front().ok_or(DecodeError::UnexpectedEof)?;
+    let low = (first & 0x0F) as u64;
+    if first & TAGGED_OVERFLOW == 0 {
+      return Ok((first >> 5, low));
+    }
+    let high = Decoder::read_u64(data)?;
+    // Only values written for more than 64 bits have any of the top bits set.
+    if high >> 60 != 0 {
+      return Err(DecodeError::Overflow);
+    }
+    Ok((first >> 5, high << 4 | low))
+  }
+
+  /// Decodes varints into `out` until it is full or `data` is empty, and returns
+  /// how many were decoded. Input ending inside a varint is an error.
+  pub fn decode_u64_slice_into(
+    data: &mut VecDeque<u8>,
+    out: &mut [u64],
+  ) -> Result<usize, DecodeError> {
+    Decoder::decode_u64_slice_into_with::<LeaflessVarint>(data, out)
+  }
+
+  /// Decodes varints in the scheme `C` like [`Decoder::decode_u64_slice_into`].
+  pub fn decode_u64_slice_into_with<C: VarintCodec>(
+    data: &mut VecDeque<u8>,
+    out: &mut [u64],
+  ) -> Result<usize, DecodeError> {
+    for (i, value) in out.iter_mut().enumerate() {
+      if data.is_empty() {
+        return Ok(i);
+      }
+      *value = C::decode(data)?;
+    }
+    Ok(out.len())
+  }
+
+  /// Decodes entries written by [`Encoder::encode_map`], in encoded order.
+  pub fn decode_map(data: &mut VecDeque<u8>) -> Result<Vec<(Vec<u8>, u64)>, DecodeError> {
+    Decoder::decode_map_with::<LeaflessVarint>(data)
+  }
+
+  /// Decodes entries written by [`Encoder::encode_map_with`].
+  pub fn decode_map_with<C: VarintCodec>(
+    data: &mut VecDeque<u8>,
+  ) -> Result<Vec<(Vec<u8>, u64)>, DecodeError> {
+    let len = C::decode(data)?;
+    // Every entry takes at least two bytes, which caps a corrupt count.
+    let mut entries = Vec::with_capacity(len.min(data.len() as u64 / 2) as usize);
+    for _ in 0..len {
+      let key_len = C::decode(data)?;
+      if key_len > data.len() as u64 {
+        return Err(DecodeError::UnexpectedEof);
+      }
+      let key: Vec<u8> = data.drain(..key_len as usize).collect();
+      entries.push((key, C::decode(data)?));
+    }
+    Ok(entries)
+  }
+
+  /// Decodes the varints held by the first `len` bytes of `data`, e.g. the real
+  /// payload of a padded block, and leaves the trailing bytes in `data` untouched.
+  /// A varint crossing the boundary is an error.
+  pub fn decode_u64_within(data: &mut VecDeque<u8>, len: usize) -> Result<Vec<u64>, DecodeError> {
+    Decoder::decode_u64_within_with::<LeaflessVarint>(data, len)
+  }
+
+  /// Decodes varints in the scheme `C` like [`Decoder::decode_u64_within`].
+  pub fn decode_u64_within_with<C: VarintCodec>(
+    data: &mut VecDeque<u8>,
+    len: usize,
+  ) -> Result<Vec<u64>, DecodeError> {
+    if len > data.len() {
+      return Err(DecodeError::UnexpectedEof);
+    }
+    let mut record = data.drain(..len).collect::<VecDeque<u8>>();
+    let mut values = Vec::new();
+    while !record.is_empty() {
+      values.push(C::decode(&mut record)?);
+    }
+    Ok(values)
+  }
+
+  pub fn decode_u64_slice(data: &mut VecDeque<u8>) -> Result<Vec<u64>, DecodeError> {
+    Vec::<u64>::decode(data)
+  }
+
+  /// Decodes a value written by [`Encoder::encode_u64_with`].
+  pub fn decode_u64_with<C: VarintCodec>(data: &mut VecDeque<u8>) -> Result<u64, DecodeError> {
+    C::decode(data)
+  }
+
+  /// Decodes values written by [`Encoder::encode_u64_slice_with`].
+  pub fn decode_u64_slice_with<C: VarintCodec>(
+    data: &mut VecDeque<u8>,
+  ) -> Result<Vec<u64>, DecodeError> {
+    let len = C::decode(data)?;
+    // Every value takes at least one byte, which bounds a corrupt count.
+    let mut values = Vec::with_capacity(len.min(data.len() as u64) as usize);
+    for _ in 0..len {
+      values.push(C::decode(data)?);
+    }
+    Ok(values)
+  }
+
+  /// Reads one varint from `reader`, failing if it ends before the value does.
+  pub fn read_u64<R: Read>(reader: &mut R) -> Result<u64, DecodeError> {
+    let mut value: u64 = 0;
+    for i in 0..8 {
+      let byte = Decoder::read_byte(reader)?;
+      value += ((byte & (SEVEN_BITS as u8)) as u64) << (BITS_PER_BYTE * i);
+      if (byte & HAS_NEXT) == 0 {
+        return Ok(value);
+      }
+    }
+    let byte = Decoder::read_byte(reader)?;
+    Ok(value + ((byte as u64) << (BITS_PER_BYTE * 8)))
+  }
+
+  /// Decodes consecutive records from `reader` with `decode` until the input ends.
+  ///
+  /// The iterator stops cleanly when the input ends between two records. An error,
+  /// including input ending inside a record, is yielded once and ends iteration.
+  pub fn records<R, T, F>(reader: R, decode: F) -> Records<R, F>
+  where
+    R: Read,
+    F: FnMut(&mut io::BufReader<R>) -> Result<T, DecodeError>,
+  {
+    Records {
+      reader: io::BufReader::new(reader),
+      decode,
+      done: false,
+    }
+  }
+
+  fn read_byte<R: Read>(reader: &mut R) -> Result<u8, DecodeError> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+  }
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::encoding::{Decoder, Encoder};
+  use crate::encoding::{
+    Decode, DecodeError, DecodeNested, Decoder, DepthLimit, Encode, Encoder, LeaflessVarint,
+    Leb128, TAGGED_OVERFLOW,
+  };
+  use std::collections::VecDeque;
+  use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
   #[test]
   fn test_u64_encoding() {
@@ -65,4 +873,587 @@ mod tests {
       assert_eq!(Decoder::decode_u64(&mut Encoder::encode_u64(case)), case);
     }
   }
+
+  #[test]
+  fn test_varint_codecs() {
+    for case in [
+      0,
+      1,
+      127,
+      128,
+      300,
+      1 << 56,
+      (1 << 63) - 1,
+      1 << 63,
+      u64::MAX,
+    ] {
+      let mut leafless = Encoder::encode_u64_with::<LeaflessVarint>(case);
+      let mut leb128 = Encoder::encode_u64_with::<Leb128>(case);
+      assert_eq!(leafless, Encoder::encode_u64(case));
+      // The schemes only part ways once the 9th byte needs its top bit.
+      assert_eq!(leafless == leb128, case < 1 << 63);
+      assert_eq!(
+        Decoder::decode_u64_with::<LeaflessVarint>(&mut leafless).unwrap(),
+        case
+      );
+      assert_eq!(
+        Decoder::decode_u64_with::<Leb128>(&mut leb128).unwrap(),
+        case
+      );
+    }
+    // 150 as in the protobuf encoding guide.
+    assert_eq!(
+      Vec::from(Encoder::encode_u64_with::<Leb128>(150)),
+      vec![0x96, 0x01]
+    );
+    assert_eq!(
+      Encoder::encode_u64_with::<LeaflessVarint>(u64::MAX).len(),
+      9
+    );
+    assert_eq!(Encoder::encode_u64_with::<Leb128>(u64::MAX).len(), 10);
+
+    let mut too_long = VecDeque::from(vec![0xFF; 9]);
+    too_long.push_back(0x02);
+    assert!(matches!(
+      Decoder::decode_u64_with::<Leb128>(&mut too_long),
+      Err(DecodeError::Overflow)
+    ));
+    assert!(matches!(
+      Decoder::decode_u64_with::<Leb128>(&mut VecDeque::from(vec![0x80])),
+      Err(DecodeError::UnexpectedEof)
+    ));
+
+    let values = [5, 1 << 60, 0];
+    let mut data = Encoder::encode_u64_slice_with::<Leb128>(&values);
+    assert_eq!(
+      Decoder::decode_u64_slice_with::<Leb128>(&mut data).unwrap(),
+      values
+    );
+    assert!(data.is_empty());
+
+    let entries: [(&[u8], u64); 2] = [(b"a", u64::MAX), (b"", 3)];
+    let mut data = Encoder::encode_map_with::<Leb128>(&entries);
+    assert_eq!(data.len(), 1 + 2 + 10 + 2);
+    let decoded = Decoder::decode_map_with::<Leb128>(&mut data).unwrap();
+    assert_eq!(decoded, vec![(b"a".to_vec(), u64::MAX), (Vec::new(), 3)]);
+
+    let data = Encoder::encode_kv_entries_with::<Leb128>(&[(b"k", b"v")]);
+    assert_eq!(
+      Decoder::decode_kv_entries_with::<Leb128>(&Vec::from(data)).unwrap(),
+      vec![(b"k".to_vec(), b"v".to_vec())]
+    );
+
+    let mut data = Encoder::encode_u64_with::<Leb128>(u64::MAX);
+    let len = data.len();
+    assert_eq!(
+      Decoder::decode_u64_within_with::<Leb128>(&mut data, len).unwrap(),
+      vec![u64::MAX]
+    );
+  }
+
+  #[test]
+  fn test_u64_array_encoding() {
+    for case in [0, 1, 127, 128, 300, 1 << 56, u64::MAX] {
+      let (array, len) = Encoder::encode_u64_array(case);
+      assert_eq!(
+        &array[..len],
+        Vec::from(Encoder::encode_u64(case)).as_slice()
+      );
+      assert_eq!(len, Encoder::encoded_len(case));
+    }
+  }
+
+  #[test]
+  fn test_usize_encoding() {
+    for case in [0, 300, usize::MAX] {
+      let mut data = Encoder::encode_usize(case);
+      assert_eq!(data, Encoder::encode_u64(case as u64));
+      assert_eq!(Decoder::decode_usize(&mut data).unwrap(), case);
+    }
+    let mut large = Encoder::encode_u64(u32::MAX as u64 + 1);
+    let decoded = Decoder::decode_usize(&mut large);
+    if cfg!(target_pointer_width = "32") {
+      assert!(matches!(decoded, Err(DecodeError::Overflow)));
+    } else {
+      assert_eq!(decoded.unwrap() as u64, u32::MAX as u64 + 1);
+    }
+  }
+
+  #[test]
+  fn test_canonical_u64() {
+    for case in [0, 127, 128, 1 << 56, u64::MAX] {
+      let mut data = Encoder::encode_u64(case);
+      assert_eq!(Decoder::decode_u64_canonical(&mut data).unwrap(), case);
+    }
+    let mut nine_bytes = vec![0x80; 8];
+    nine_bytes.push(0x00);
+    for bytes in [vec![0x80, 0x00], vec![0xFF, 0x80, 0x00], nine_bytes] {
+      assert!(matches!(
+        Decoder::decode_u64_canonical(&mut VecDeque::from(bytes)),
+        Err(DecodeError::NonCanonical)
+      ));
+    }
+  }
+
+  #[test]
+  fn test_write_u64() {
+    let cases = [0, 0x7F, 300, 1 << 56, u64::MAX];
+    let mut out = Vec::new();
+    for case in cases {
+      Encoder::write_u64(case, &mut out).unwrap();
+    }
+    let mut reader = out.as_slice();
+    for case in cases {
+      assert_eq!(Decoder::read_u64(&mut reader).unwrap(), case);
+    }
+    assert!(reader.is_empty());
+  }
+
+  #[test]
+  fn test_option_u64_encoding() {
+    for case in [Some(0), Some(u64::MAX), None] {
+      let mut data = Encoder::encode_option_u64(case);
+      assert_eq!(Decoder::decode_option_u64(&mut data).unwrap(), case);
+      assert!(data.is_empty());
+    }
+    assert_eq!(Vec::from(Encoder::encode_option_u64(None)), vec![0x00]);
+    assert_eq!(
+      Vec::from(Encoder::encode_option_u64(Some(0))),
+      vec![0x01, 0x00]
+    );
+    assert!(matches!(
+      Decoder::decode_option_u64(&mut VecDeque::from(vec![0x02])),
+      Err(DecodeError::InvalidTag(2))
+    ));
+    assert!(matches!(
+      Decoder::decode_option_u64(&mut VecDeque::from(vec![0x01])),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_either_encoding() {
+    let cases: [Result<u64, Option<u64>>; 3] = [Ok(300), Err(Some(7)), Err(None)];
+    for case in cases {
+      let mut data = Encoder::encode_either(&case);
+      assert_eq!(data.len(), case.encoded_size());
+      assert_eq!(Decoder::decode_either(&mut data).unwrap(), case);
+      assert!(data.is_empty());
+    }
+    assert_eq!(
+      Vec::from(Encoder::encode_either::<u64, u64>(&Err(5))),
+      vec![0x01, 0x05]
+    );
+    assert!(matches!(
+      Decoder::decode_either::<u64, u64>(&mut VecDeque::from(vec![0x02, 0x05])),
+      Err(DecodeError::InvalidTag(2))
+    ));
+    assert!(matches!(
+      Decoder::decode_either::<u64, u64>(&mut VecDeque::new()),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_nested_option_encoding() {
+    for case in [Some(Some(7u64)), Some(None), None] {
+      let mut buf = VecDeque::new();
+      case.encode(&mut buf);
+      assert_eq!(Option::<Option<u64>>::decode(&mut buf).unwrap(), case);
+    }
+  }
+
+  #[test]
+  fn test_u64_slice_encoding() {
+    let values = [0, 300, u64::MAX];
+    let mut data = Encoder::encode_u64_slice(&values);
+    assert_eq!(data.len(), 1 + 1 + 2 + 9);
+    assert_eq!(Decoder::decode_u64_slice(&mut data).unwrap(), values);
+    assert!(data.is_empty());
+    assert!(matches!(
+      Decoder::decode_u64_slice(&mut VecDeque::from(vec![0xFF, 0xFF, 0x01, 0x00])),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_u64_within() {
+    let mut data = Encoder::encode_u64(300);
+    data.extend(Encoder::encode_u64(7));
+    let len = data.len();
+    data.extend([0; 5]);
+    assert_eq!(
+      Decoder::decode_u64_within(&mut data, len).unwrap(),
+      vec![300, 7]
+    );
+    assert_eq!(Vec::from(data), vec![0; 5]);
+
+    let mut data = Encoder::encode_u64(300);
+    assert!(matches!(
+      Decoder::decode_u64_within(&mut data, 1),
+      Err(DecodeError::UnexpectedEof)
+    ));
+    assert!(matches!(
+      Decoder::decode_u64_within(&mut VecDeque::new(), 1),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_u64_slice_into() {
+    let mut data = VecDeque::new();
+    for value in [5u64, 300, 0, u64::MAX, 7] {
+      value.encode(&mut data);
+    }
+    let mut out = [0u64; 4];
+    assert_eq!(
+      Decoder::decode_u64_slice_into(&mut data, &mut out).unwrap(),
+      4
+    );
+    assert_eq!(out, [5, 300, 0, u64::MAX]);
+    assert_eq!(
+      Decoder::decode_u64_slice_into(&mut data, &mut out).unwrap(),
+      1
+    );
+    assert_eq!(out[0], 7);
+    assert_eq!(
+      Decoder::decode_u64_slice_into(&mut data, &mut out).unwrap(),
+      0
+    );
+    assert!(matches!(
+      Decoder::decode_u64_slice_into(&mut VecDeque::from(vec![0x80]), &mut out),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_tagged_encoding() {
+    assert_eq!(Vec::from(Encoder::encode_tagged(5, 9)), vec![0b1010_1001]);
+    assert_eq!(
+      Vec::from(Encoder::encode_tagged(1, 0x123)),
+      vec![0b0011_0011, 0x12]
+    );
+    for (tag, value) in [(0, 0), (7, 15), (3, 16), (2, 300), (7, u64::MAX)] {
+      let mut data = Encoder::encode_tagged(tag, value);
+      assert_eq!(data.len() == 1, value < 16);
+      assert_eq!(Decoder::decode_tagged(&mut data).unwrap(), (tag, value));
+      assert!(data.is_empty());
+    }
+    assert!(matches!(
+      Decoder::decode_tagged(&mut VecDeque::from(vec![TAGGED_OVERFLOW])),
+      Err(DecodeError::UnexpectedEof)
+    ));
+    let mut overflowing = Encoder::encode_u64(1 << 60);
+    overflowing.push_front(TAGGED_OVERFLOW);
+    assert!(matches!(
+      Decoder::decode_tagged(&mut overflowing),
+      Err(DecodeError::Overflow)
+    ));
+  }
+
+  #[test]
+  fn test_group_varint() {
+    let mut data = Encoder::encode_group_varint(&[1, 0x1234, 0x10_0000, 1 << 40]);
+    // count, control, then 1 + 2 + 4 + 8 value bytes.
+    assert_eq!(data.len(), 1 + 1 + 15);
+    assert_eq!(data[1], 0b11_10_01_00);
+    assert_eq!(
+      Decoder::decode_group_varint(&mut data).unwrap(),
+      vec![1, 0x1234, 0x10_0000, 1 << 40]
+    );
+    for len in [0, 1, 3, 5, 7, 10] {
+      let values = (0..len)
+        .map(|i| i * 0x1357_9BDF_u64.pow(i as u32 % 3))
+        .collect::<Vec<_>>();
+      let mut data = Encoder::encode_group_varint(&values);
+      assert_eq!(Decoder::decode_group_varint(&mut data).unwrap(), values);
+      assert!(data.is_empty());
+    }
+    let mut truncated = Encoder::encode_group_varint(&[1, 2, 0xFFFF]);
+    truncated.pop_back();
+    assert!(matches!(
+      Decoder::decode_group_varint(&mut truncated),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_fixed_encoding() {
+    let hash: [u8; 32] = std::array::from_fn(|i| (i * 7) as u8);
+    let mut data = Encoder::encode_fixed(&hash);
+    data.push_back(0xAA);
+    assert_eq!(data.len(), 33);
+    assert_eq!(Decoder::decode_fixed::<32>(&mut data).unwrap(), hash);
+    assert_eq!(Vec::from(data), vec![0xAA]);
+    let mut short = Encoder::encode_fixed(&[1u8; 31]);
+    assert!(matches!(
+      Decoder::decode_fixed::<32>(&mut short),
+      Err(DecodeError::UnexpectedEof)
+    ));
+    assert_eq!(short.len(), 31);
+  }
+
+  #[test]
+  fn test_le_fixed_encoding() {
+    let mut data = Encoder::encode_u64_le_fixed(0x0102_0304_0506_0708);
+    assert_eq!(
+      Vec::from(data.clone()),
+      vec![0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+    );
+    data.extend(Encoder::encode_u32_le_fixed(0xDEAD_BEEF));
+    assert_eq!(Vec::from(data.clone())[8..], [0xEF, 0xBE, 0xAD, 0xDE]);
+    assert_eq!(
+      Decoder::decode_u64_le_fixed(&mut data).unwrap(),
+      0x0102_0304_0506_0708
+    );
+    assert_eq!(
+      Decoder::decode_u32_le_fixed(&mut data).unwrap(),
+      0xDEAD_BEEF
+    );
+    assert!(matches!(
+      Decoder::decode_u32_le_fixed(&mut data),
+      Err(DecodeError::UnexpectedEof)
+    ));
+    // 256 sorts before 1 as bytes.
+    assert!(Encoder::encode_u64_le_fixed(256) < Encoder::encode_u64_le_fixed(1));
+  }
+
+  #[test]
+  fn test_ip_encoding() {
+    let v4 = Ipv4Addr::new(192, 168, 0, 1);
+    let mut data = Encoder::encode_ipv4(v4);
+    assert_eq!(Vec::from(data.clone()), vec![192, 168, 0, 1]);
+    assert_eq!(Decoder::decode_ipv4(&mut data).unwrap(), v4);
+    let v6 = "2001:db8::8a2e:370:7334".parse::<Ipv6Addr>().unwrap();
+    let mut data = Encoder::encode_ipv6(v6);
+    assert_eq!(data.len(), 16);
+    assert_eq!(Decoder::decode_ipv6(&mut data).unwrap(), v6);
+
+    let addrs = [
+      SocketAddr::new(IpAddr::V4(v4), 8080),
+      SocketAddr::new(IpAddr::V6(v6), 0),
+      SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), u16::MAX),
+    ];
+    let mut data = VecDeque::new();
+    for addr in addrs {
+      data.extend(Encoder::encode_socket_addr(addr));
+    }
+    for addr in addrs {
+      assert_eq!(Decoder::decode_socket_addr(&mut data).unwrap(), addr);
+    }
+    assert!(data.is_empty());
+    assert_eq!(Encoder::encode_socket_addr(addrs[0]).len(), 1 + 4 + 2);
+
+    assert!(matches!(
+      Decoder::decode_socket_addr(&mut VecDeque::from(vec![5, 1, 2, 3, 4, 0])),
+      Err(DecodeError::InvalidTag(5))
+    ));
+    let mut large_port = VecDeque::from(vec![4, 1, 2, 3, 4]);
+    large_port.extend(Encoder::encode_u64(1 << 16));
+    assert!(matches!(
+      Decoder::decode_socket_addr(&mut large_port),
+      Err(DecodeError::Overflow)
+    ));
+  }
+
+  #[test]
+  fn test_kv_entries() {
+    let entries: [(&[u8], &[u8]); 3] = [(b"key", b"value"), (b"", &[0, 255]), (&[7; 200], b"")];
+    let mut block = Vec::from(Encoder::encode_kv_entries(&entries));
+    block.resize(4096, 0);
+    let decoded = Decoder::decode_kv_entries(&block).unwrap();
+    assert_eq!(decoded.len(), 3);
+    for ((key, value), (decoded_key, decoded_value)) in entries.iter().zip(&decoded) {
+      assert_eq!(key, decoded_key);
+      assert_eq!(value, decoded_value);
+    }
+    assert!(Decoder::decode_kv_entries(&[0]).unwrap().is_empty());
+
+    let truncated = Vec::from(Encoder::encode_kv_entries(&entries));
+    assert!(matches!(
+      Decoder::decode_kv_entries(&truncated[..12]),
+      Err(DecodeError::UnexpectedEof)
+    ));
+    assert!(matches!(
+      Decoder::decode_kv_entries(&[1, 3, b'k']),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  #[test]
+  fn test_map_encoding() {
+    let mut empty = Encoder::encode_map(&[]);
+    assert_eq!(Vec::from(empty.clone()), vec![0]);
+    assert!(Decoder::decode_map(&mut empty).unwrap().is_empty());
+
+    let entries: [(&[u8], u64); 3] = [(b"zeta", 1), (&[0x00, 0xFF, 0x80], 300), (b"", u64::MAX)];
+    let mut data = Encoder::encode_map(&entries);
+    let decoded = Decoder::decode_map(&mut data).unwrap();
+    assert!(data.is_empty());
+    assert_eq!(decoded.len(), 3);
+    for ((key, value), (decoded_key, decoded_value)) in entries.iter().zip(&decoded) {
+      assert_eq!(key, decoded_key);
+      assert_eq!(value, decoded_value);
+    }
+
+    let mut truncated = Encoder::encode_map(&entries);
+    truncated.truncate(4);
+    assert!(matches!(
+      Decoder::decode_map(&mut truncated),
+      Err(DecodeError::UnexpectedEof)
+    ));
+  }
+
+  /// A tree node encoded as its child count followed by the children.
+  #[derive(Debug, PartialEq)]
+  struct Node(Vec<Node>);
+
+  impl Encode for Node {
+    fn encode(&self, buf: &mut VecDeque<u8>) {
+      self.0.as_slice().encode(buf);
+    }
+  }
+
+  impl DecodeNested for Node {
+    fn decode_nested(data: &mut VecDeque<u8>, limit: DepthLimit) -> Result<Node, DecodeError> {
+      let limit = limit.nested()?;
+      let count = u64::decode(data)?;
+      let mut children = Vec::new();
+      for _ in 0..count {
+        children.push(Node::decode_nested(data, limit)?);
+      }
+      Ok(Node(children))
+    }
+  }
+
+  #[test]
+  fn test_nested_depth_limit() {
+    let tree = Node(vec![Node(vec![Node(vec![])]), Node(vec![])]);
+    let mut buf = VecDeque::new();
+    tree.encode(&mut buf);
+    assert_eq!(
+      Decoder::decode_nested::<Node>(&mut buf.clone(), 3).unwrap(),
+      tree
+    );
+    assert!(matches!(
+      Decoder::decode_nested::<Node>(&mut buf, 2),
+      Err(DecodeError::DepthExceeded)
+    ));
+
+    // A million nodes with one child each would overflow the stack without the limit.
+    let mut crafted = VecDeque::from(vec![0x01; 1_000_000]);
+    assert!(matches!(
+      Decoder::decode_nested::<Node>(&mut crafted, 64),
+      Err(DecodeError::DepthExceeded)
+    ));
+  }
+
+  #[test]
+  fn test_encoded_size() {
+    for value in [0, 0x7F, 0x80, 300, (1 << 56) - 1, 1 << 56, u64::MAX] {
+      assert_eq!(
+        Encoder::encoded_len(value),
+        Encoder::encode_u64(value).len()
+      );
+    }
+
+    struct Entry {
+      key: u64,
+      value: Option<u64>,
+      children: Vec<u64>,
+    }
+
+    impl Encode for Entry {
+      fn encode(&self, buf: &mut VecDeque<u8>) {
+        self.key.encode(buf);
+        self.value.encode(buf);
+        self.children.as_slice().encode(buf);
+      }
+
+      fn encoded_size(&self) -> usize {
+        self.key.encoded_size() + self.value.encoded_size() + self.children.encoded_size()
+      }
+    }
+
+    let entry = Entry {
+      key: 300,
+      value: Some(u64::MAX),
+      children: vec![1, 1 << 20, 0],
+    };
+    let mut buf = VecDeque::new();
+    entry.encode(&mut buf);
+    assert_eq!(entry.encoded_size(), buf.len());
+    assert_eq!(buf.len(), 2 + 10 + 1 + 5);
+  }
+
+  #[test]
+  fn test_u64_golden_bytes() {
+    let cases: Vec<(u64, Vec<u8>)> = vec![
+      (0, vec![0x00]),
+      (1, vec![0x01]),
+      (0x7F, vec![0x7F]),
+      (0x80, vec![0x80, 0x01]),
+      (300, vec![0xAC, 0x02]),
+      (0xFF_00_00, vec![0x80, 0x80, 0xFC, 0x07]),
+      (
+        1 << 56,
+        vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01],
+      ),
+      (u64::MAX, vec![0xFF; 9]),
+    ];
+    for (value, bytes) in cases {
+      assert_eq!(Vec::from(Encoder::encode_u64(value)), bytes, "{:#x}", value);
+    }
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum Record {
+    Put(u64, u64),
+    Delete(u64),
+    Clear,
+  }
+
+  fn encode_record(record: &Record) -> Vec<u8> {
+    let mut buf = std::collections::VecDeque::new();
+    match record {
+      Record::Put(key, value) => {
+        buf.extend(Encoder::encode_u64(0));
+        buf.extend(Encoder::encode_u64(*key));
+        buf.extend(Encoder::encode_u64(*value));
+      }
+      Record::Delete(key) => {
+        buf.extend(Encoder::encode_u64(1));
+        buf.extend(Encoder::encode_u64(*key));
+      }
+      Record::Clear => buf.extend(Encoder::encode_u64(2)),
+    }
+    buf.into()
+  }
+
+  fn decode_record<R: std::io::Read>(reader: &mut R) -> Result<Record, DecodeError> {
+    match Decoder::read_u64(reader)? {
+      0 => Ok(Record::Put(
+        Decoder::read_u64(reader)?,
+        Decoder::read_u64(reader)?,
+      )),
+      1 => Ok(Record::Delete(Decoder::read_u64(reader)?)),
+      _ => Ok(Record::Clear),
+    }
+  }
+
+  #[test]
+  fn test_records() {
+    let records = [Record::Put(1, 300), Record::Delete(1), Record::Clear];
+    let data = records.iter().flat_map(encode_record).collect::<Vec<_>>();
+    let mut decoded = Decoder::records(data.as_slice(), decode_record);
+    assert!(matches!(decoded.next(), Some(Ok(Record::Put(1, 300)))));
+    assert!(matches!(decoded.next(), Some(Ok(Record::Delete(1)))));
+    assert!(matches!(decoded.next(), Some(Ok(Record::Clear))));
+    assert!(decoded.next().is_none());
+
+    let truncated = &data[..data.len() - 2];
+    let decoded = Decoder::records(truncated, decode_record).collect::<Vec<_>>();
+    assert_eq!(decoded.len(), 2);
+    assert!(matches!(decoded[1], Err(DecodeError::UnexpectedEof)));
+  }
 }
@@ -0,0 +1,72 @@
+use std::{error::Error, fmt, io};
+
+/// Errors specific to leafless, carried inside an [`io::Error`] by the storage APIs.
+///
+/// Use [`LeaflessError::from_io`] to recover it from a returned error.
+#[derive(Debug)]
+pub enum LeaflessError {
+  /// A write failed after `written` of `len` bytes were handed to the file.
+  Write {
+    written: u64,
+    len: u64,
+    source: io::Error,
+  },
+  /// Another handle holds the lock on the storage file.
+  Locked,
+  /// The block at `offset` failed verification.
+  Corrupted { offset: u64, reason: String },
+}
+
+impl LeaflessError {
+  pub fn from_io(err: &io::Error) -> Option<&LeaflessError> {
+    err.get_ref()?.downcast_ref::<LeaflessError>()
+  }
+
+  /// Whether some, but not all, of the data reached the file before the failure.
+  pub fn is_partial(&self) -> bool {
+    match self {
+      LeaflessError::Write { written, len, .. } => *written > 0 && written < len,
+      LeaflessError::Locked | LeaflessError::Corrupted { .. } => false,
+    }
+  }
+}
+
+impl fmt::Display for LeaflessError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LeaflessError::Write {
+        written,
+        len,
+        source,
+      } => write!(
+        f,
+        "Write failed after {} of {} bytes: {}",
+        written, len, source
+      ),
+      LeaflessError::Locked => write!(f, "Storage file is locked by another handle"),
+      LeaflessError::Corrupted { offset, reason } => {
+        write!(f, "Block {} is corrupted: {}", offset, reason)
+      }
+    }
+  }
+}
+
+impl Error for LeaflessError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      LeaflessError::Write { source, .. } => Some(source),
+      LeaflessError::Locked | LeaflessError::Corrupted { .. } => None,
+    }
+  }
+}
+
+impl From<LeaflessError> for io::Error {
+  fn from(err: LeaflessError) -> io::Error {
+    let kind = match &err {
+      LeaflessError::Write { source, .. } => source.kind(),
+      LeaflessError::Locked => io::ErrorKind::WouldBlock,
+      LeaflessError::Corrupted { .. } => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, err)
+  }
+}
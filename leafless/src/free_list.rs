@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+
+use crate::{
+  allocator::Allocator,
+  encoding::{Decoder, Encoder},
+};
+
+/// The default [`Allocator`]: free block extents as `(offset, count)`, sorted
+/// by offset and coalesced, handed out first-fit.
+pub struct FreeList {
+  extents: Vec<(u64, u64)>,
+  dirty: bool,
+}
+
+impl FreeList {
+  pub fn new() -> FreeList {
+    FreeList {
+      extents: Vec::new(),
+      dirty: true,
+    }
+  }
+
+  /// The free extents as `(offset, count)`, in ascending offset order.
+  pub fn extents(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+    self.extents.iter().copied()
+  }
+
+  /// Number of contiguous free extents.
+  pub fn extent_count(&self) -> usize {
+    self.extents.len()
+  }
+
+  /// Takes the `count` blocks at `offset` if all of them are free.
+  pub fn take(&mut self, offset: u64, count: u64) -> bool {
+    let index = self.extents.partition_point(|&(start, _)| start <= offset);
+    let Some(index) = index.checked_sub(1) else {
+      return false;
+    };
+    let (start, size) = self.extents[index];
+    if offset.saturating_add(count) > start + size {
+      return false;
+    }
+    self.dirty = true;
+    let tail = (offset + count, start + size - offset - count);
+    match (offset > start, tail.1 > 0) {
+      (true, true) => {
+        self.extents[index].1 = offset - start;
+        self.extents.insert(index + 1, tail);
+      }
+      (true, false) => self.extents[index].1 = offset - start,
+      (false, true) => self.extents[index] = tail,
+      (false, false) => {
+        self.extents.remove(index);
+      }
+    }
+    true
+  }
+
+  /// Releases the capacity left behind by extents that were taken or coalesced.
+  pub fn shrink_to_fit(&mut self) {
+    self.extents.shrink_to_fit();
+  }
+
+  /// Number of free blocks over all extents.
+  pub fn free_blocks(&self) -> u64 {
+    self.extents.iter().map(|&(_, count)| count).sum()
+  }
+}
+
+impl Default for FreeList {
+  fn default() -> FreeList {
+    FreeList::new()
+  }
+}
+
+impl Allocator for FreeList {
+  /// Takes `count` blocks from the first extent large enough to hold them.
+  fn allocate(&mut self, count: u64) -> Option<u64> {
+    let index = self.extents.iter().position(|&(_, size)| size >= count)?;
+    let (offset, size) = self.extents[index];
+    self.dirty = true;
+    if size == count {
+      self.extents.remove(index);
+    } else {
+      self.extents[index] = (offset + count, size - count);
+    }
+    Some(offset)
+  }
+
+  fn peek(&self, count: u64) -> Option<u64> {
+    self
+      .extents
+      .iter()
+      .find(|&&(_, size)| size >= count)
+      .map(|&(offset, _)| offset)
+  }
+
+  /// Returns `count` blocks starting at `offset`, merging them with adjacent extents.
+  ///
+  /// The blocks must not be free already, callers such as
+  /// [`BlockStorage::freeBlock`](crate::block_storage::BlockStorage::freeBlock)
+  /// check this.
+  fn deallocate(&mut self, offset: u64, count: u64) {
+    debug_assert!(
+      !self.overlaps_free(offset, count),
+      "Blocks {}..{} are already free",
+      offset,
+      offset + count
+    );
+    let index = self.extents.partition_point(|&(start, _)| start < offset);
+    self.dirty = true;
+    self.extents.insert(index, (offset, count));
+    if index + 1 < self.extents.len() {
+      let (next_offset, next_count) = self.extents[index + 1];
+      if offset + count == next_offset {
+        self.extents[index].1 += next_count;
+        self.extents.remove(index + 1);
+      }
+    }
+    if index > 0 {
+      let (prev_offset, prev_count) = self.extents[index - 1];
+      if prev_offset + prev_count == offset {
+        self.extents[index - 1].1 += self.extents[index].1;
+        self.extents.remove(index);
+      }
+    }
+  }
+
+  fn overlaps_free(&self, offset: u64, count: u64) -> bool {
+    self
+      .extents
+      .iter()
+      .any(|&(start, size)| start < offset + count && offset < start + size)
+  }
+
+  fn serialize(&self) -> VecDeque<u8> {
+    let mut buf = Encoder::encode_u64(self.extents.len() as u64);
+    for &(offset, count) in &self.extents {
+      buf.extend(Encoder::encode_u64(offset));
+      buf.extend(Encoder::encode_u64(count));
+    }
+    buf
+  }
+
+  fn deserialize(&mut self, data: &mut VecDeque<u8>) {
+    let len = Decoder::decode_u64(data);
+    self.extents.clear();
+    for _ in 0..len {
+      let offset = Decoder::decode_u64(data);
+      let count = Decoder::decode_u64(data);
+      self.extents.push((offset, count));
+    }
+    self.dirty = false;
+  }
+
+  fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  fn mark_clean(&mut self) {
+    self.dirty = false;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FreeList;
+  use crate::allocator::Allocator;
+
+  #[test]
+  fn test_coalesce() {
+    let mut list = FreeList::new();
+    list.deallocate(5, 1);
+    list.deallocate(1, 2);
+    list.deallocate(3, 2);
+    assert_eq!(list.extents, vec![(1, 5)]);
+    assert_eq!(list.peek(2), Some(1));
+    assert_eq!(list.allocate(2), Some(1));
+    assert_eq!(list.allocate(4), None);
+    assert_eq!(list.allocate(3), Some(3));
+    assert!(list.extents.is_empty());
+  }
+
+  #[test]
+  fn test_free_counts() {
+    let mut list = FreeList::new();
+    list.deallocate(1, 2);
+    list.deallocate(6, 3);
+    assert_eq!(list.extent_count(), 2);
+    assert_eq!(list.free_blocks(), 5);
+  }
+
+  #[test]
+  fn test_take() {
+    let mut list = FreeList::new();
+    list.deallocate(2, 6);
+    assert!(!list.take(1, 2));
+    assert!(!list.take(6, 3));
+    assert!(list.take(4, 2));
+    assert_eq!(list.extents, vec![(2, 2), (6, 2)]);
+    assert!(list.take(2, 2));
+    assert!(list.take(7, 1));
+    assert_eq!(list.extents, vec![(6, 1)]);
+    assert!(list.take(6, 1));
+    assert!(list.extents.is_empty());
+  }
+
+  #[test]
+  fn test_shrink_to_fit() {
+    let mut list = FreeList::new();
+    for offset in (1..2000).step_by(2) {
+      list.deallocate(offset, 1);
+    }
+    for offset in (2..2000).step_by(2) {
+      list.deallocate(offset, 1);
+    }
+    assert_eq!(list.extents, vec![(1, 1999)]);
+    assert!(list.extents.capacity() >= 1000);
+    list.shrink_to_fit();
+    assert!(list.extents.capacity() < 1000);
+    assert_eq!(list.extents, vec![(1, 1999)]);
+  }
+
+  #[test]
+  fn test_serialize() {
+    let mut list = FreeList::new();
+    list.deallocate(2, 1);
+    list.deallocate(8, 3);
+    let mut decoded = FreeList::new();
+    decoded.deserialize(&mut list.serialize());
+    assert_eq!(decoded.extents, list.extents);
+  }
+}
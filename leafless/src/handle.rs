@@ -0,0 +1,131 @@
+use std::{cell::RefCell, collections::VecDeque, io};
+
+use crate::{
+  allocator::Allocator,
+  block_storage::{BlockStorage, DataBlock},
+  encoding::{LeaflessVarint, VarintCodec},
+  free_list::FreeList,
+};
+
+/// A claimed block bound to the storage it belongs to, so it can neither outlive
+/// the storage nor be used with another one.
+///
+/// A [`DataBlock`] is only offsets, which suits persisting it, while this shares a
+/// borrow of the storage for as long as the handle is used. Any number of handles
+/// can share one storage, each borrowing it mutably only for the duration of a call:
+///
+/// ```compile_fail,E0505
+/// use leafless::{block_storage::BlockStorage, handle::BlockHandle};
+///
+/// let file = std::fs::File::options()
+///   .read(true)
+///   .write(true)
+///   .create(true)
+///   .open(std::env::temp_dir().join("handle.leafless"))
+///   .unwrap();
+/// let storage = std::cell::RefCell::new(BlockStorage::create(file).unwrap());
+/// let handle = BlockHandle::claim(&storage, 1).unwrap();
+/// drop(storage);
+/// handle.read().unwrap();
+/// ```
+pub struct BlockHandle<'a, A: Allocator = FreeList, C: VarintCodec = LeaflessVarint> {
+  storage: &'a RefCell<BlockStorage<A, C>>,
+  block: DataBlock,
+}
+
+impl<'a, A: Allocator, C: VarintCodec> BlockHandle<'a, A, C> {
+  /// Claims `count` blocks of `storage`, see [`BlockStorage::claimBlock`].
+  pub fn claim(
+    storage: &'a RefCell<BlockStorage<A, C>>,
+    count: u64,
+  ) -> io::Result<BlockHandle<'a, A, C>> {
+    let block = storage.borrow_mut().claimBlock(count)?;
+    Ok(BlockHandle { storage, block })
+  }
+
+  /// Binds `block` to `storage`, failing with `InvalidInput` if it is no claimed
+  /// block of it, see [`BlockStorage::is_valid_block`].
+  pub fn bind(
+    storage: &'a RefCell<BlockStorage<A, C>>,
+    block: DataBlock,
+  ) -> io::Result<BlockHandle<'a, A, C>> {
+    if !storage.borrow().is_valid_block(&block) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block is not a claimed block of the storage",
+      ));
+    }
+    Ok(BlockHandle { storage, block })
+  }
+
+  pub fn block(&self) -> &DataBlock {
+    &self.block
+  }
+
+  pub fn read(&self) -> io::Result<VecDeque<u8>> {
+    self.storage.borrow_mut().readBlock(&self.block)
+  }
+
+  pub fn write(&self, data: VecDeque<u8>) -> io::Result<()> {
+    self.storage.borrow_mut().writeBlock(&self.block, data)
+  }
+
+  /// Frees the block, see [`BlockStorage::freeBlock`].
+  pub fn free(self) -> io::Result<()> {
+    self.storage.borrow_mut().freeBlock(self.block)
+  }
+
+  /// Releases the storage and returns the raw block, e.g. to persist it.
+  pub fn into_block(self) -> DataBlock {
+    self.block
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::BlockHandle;
+  use crate::block_storage::BlockStorage;
+  use rand::{self, Rng};
+  use std::cell::RefCell;
+  use std::collections::VecDeque;
+  use std::env::temp_dir;
+  use std::fs;
+
+  fn create_temp_storage() -> BlockStorage {
+    let temp_file_name: String = rand::thread_rng()
+      .sample_iter(&rand::distributions::Alphanumeric)
+      .take(16)
+      .map(char::from)
+      .collect();
+    let file = fs::File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(temp_dir().join(temp_file_name + ".leafless"))
+      .unwrap();
+    BlockStorage::create(file).unwrap()
+  }
+
+  #[test]
+  fn test_block_handle() {
+    let storage = RefCell::new(create_temp_storage());
+    let handle = BlockHandle::claim(&storage, 1).unwrap();
+    handle.write(VecDeque::from(b"bound".to_vec())).unwrap();
+    assert_eq!(Vec::from(handle.read().unwrap())[..5], *b"bound");
+    // Handles share the storage.
+    let other = BlockHandle::claim(&storage, 1).unwrap();
+    other.write(VecDeque::from(b"other".to_vec())).unwrap();
+    assert_eq!(Vec::from(handle.read().unwrap())[..5], *b"bound");
+    other.free().unwrap();
+
+    let block = handle.into_block();
+    let handle = BlockHandle::bind(&storage, block).unwrap();
+    assert_eq!(Vec::from(handle.read().unwrap())[..5], *b"bound");
+    let foreign = RefCell::new(create_temp_storage());
+    let block = foreign.borrow_mut().claimBlock(5).unwrap();
+    assert!(BlockHandle::bind(&storage, block).is_err());
+    handle.free().unwrap();
+    assert!(storage.borrow().last_block().is_none());
+  }
+}
@@ -1,2 +1,14 @@
+#[macro_use]
+mod trace;
+
+pub mod allocator;
+pub mod atomic;
 pub mod block_storage;
-mod encoding;
\ No newline at end of file
+mod checksum;
+pub mod encoding;
+pub mod error;
+pub mod free_list;
+pub mod handle;
+pub mod merge;
+pub mod metrics;
+pub mod transform;
@@ -0,0 +1,99 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+/// Merges sorted runs of `(key, value)` pairs into one run in ascending key order.
+///
+/// Runs are given newest first. A key present in several runs is yielded once,
+/// with the value of the newest run holding it. Only the next entry of every run
+/// is held in memory.
+pub struct MergeIterator<K, V, I> {
+  runs: Vec<I>,
+  heads: BinaryHeap<Head<K, V>>,
+}
+
+/// The next entry of a run, ordered so the heap yields the smallest key first and,
+/// among equal keys, the newest run.
+struct Head<K, V> {
+  key: K,
+  run: usize,
+  value: V,
+}
+
+impl<K: Ord, V> PartialEq for Head<K, V> {
+  fn eq(&self, other: &Head<K, V>) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl<K: Ord, V> Eq for Head<K, V> {}
+
+impl<K: Ord, V> PartialOrd for Head<K, V> {
+  fn partial_cmp(&self, other: &Head<K, V>) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<K: Ord, V> Ord for Head<K, V> {
+  fn cmp(&self, other: &Head<K, V>) -> Ordering {
+    (&other.key, other.run).cmp(&(&self.key, self.run))
+  }
+}
+
+impl<K: Ord, V, I: Iterator<Item = (K, V)>> MergeIterator<K, V, I> {
+  pub fn new<R: IntoIterator<IntoIter = I>>(runs: impl IntoIterator<Item = R>) -> Self {
+    let mut merge = MergeIterator {
+      runs: runs.into_iter().map(R::into_iter).collect(),
+      heads: BinaryHeap::new(),
+    };
+    for run in 0..merge.runs.len() {
+      merge.advance(run);
+    }
+    merge
+  }
+
+  fn advance(&mut self, run: usize) {
+    if let Some((key, value)) = self.runs[run].next() {
+      self.heads.push(Head { key, run, value });
+    }
+  }
+}
+
+impl<K: Ord, V, I: Iterator<Item = (K, V)>> Iterator for MergeIterator<K, V, I> {
+  type Item = (K, V);
+
+  fn next(&mut self) -> Option<(K, V)> {
+    let head = self.heads.pop()?;
+    self.advance(head.run);
+    // Older versions of the key sort right behind it.
+    while self.heads.peek().is_some_and(|next| next.key == head.key) {
+      let shadowed = self.heads.pop().unwrap();
+      self.advance(shadowed.run);
+    }
+    Some((head.key, head.value))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MergeIterator;
+
+  #[test]
+  fn test_merge() {
+    let newest = vec![(2, "c2"), (5, "c5")];
+    let middle = vec![(1, "b1"), (2, "b2"), (4, "b4"), (9, "b9")];
+    let oldest = vec![(1, "a1"), (3, "a3"), (4, "a4"), (5, "a5")];
+    let merged = MergeIterator::new([newest, middle, oldest]).collect::<Vec<_>>();
+    assert_eq!(
+      merged,
+      vec![
+        (1, "b1"),
+        (2, "c2"),
+        (3, "a3"),
+        (4, "b4"),
+        (5, "c5"),
+        (9, "b9")
+      ]
+    );
+    let empty: Vec<Vec<(u64, u64)>> = vec![vec![], vec![]];
+    assert_eq!(MergeIterator::new(empty).next(), None);
+  }
+}
@@ -0,0 +1,19 @@
+/// Cumulative I/O counters of a storage, see
+/// [`BlockStorage::metrics`](crate::block_storage::BlockStorage::metrics).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+  /// Read calls that reached the file, including meta reads.
+  pub reads: u64,
+  /// Write calls that reached the file, including meta writes.
+  pub writes: u64,
+  /// Flushes issued after writes.
+  pub flushes: u64,
+  /// Times the file was resized to make room for claims.
+  pub resizes: u64,
+  /// Times written data was forced to disk, by the sync policy or a write barrier.
+  pub syncs: u64,
+  /// Seeks of the file cursor, skipped for reads and writes where it already is.
+  pub seeks: u64,
+  pub bytes_read: u64,
+  pub bytes_written: u64,
+}
@@ -0,0 +1,8 @@
+/// Emits a `tracing` event at `$level` with the `tracing` feature enabled and
+/// compiles to nothing without it.
+macro_rules! trace {
+  ($level:ident, $($arg:tt)+) => {
+    #[cfg(feature = "tracing")]
+    tracing::event!(tracing::Level::$level, $($arg)+);
+  };
+}
@@ -0,0 +1,18 @@
+/// A reversible transformation of block payloads, such as compression or encryption,
+/// applied by [`BlockStorage::writeBlock`](crate::block_storage::BlockStorage::writeBlock)
+/// and [`BlockStorage::readBlock`](crate::block_storage::BlockStorage::readBlock).
+///
+/// The storage records the transform's `id` in its header and refuses to open with a
+/// different one, so data is never read back through the wrong transform.
+pub trait Transform {
+  /// Identifies the transform in the storage header. Must not be 0, which marks
+  /// storages without a transform.
+  fn id(&self) -> u64;
+
+  /// Transforms a payload before it is stored. The result must fit the block.
+  fn on_write(&self, data: &[u8]) -> Vec<u8>;
+
+  /// Reverses [`Transform::on_write`] on the stored bytes of a whole block payload,
+  /// including any unwritten space behind the transformed data.
+  fn on_read(&self, data: &[u8]) -> Vec<u8>;
+}